@@ -0,0 +1,42 @@
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+
+use crate::{AppState, ErrorBody};
+
+// Requires `Authorization: Bearer <API_TOKEN>` when a token is configured; with none set every
+// request passes.
+pub async fn require_token(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let Some(expected) = &state.config.api_token else {
+        return next.run(req).await;
+    };
+
+    let provided = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    match provided {
+        Some(token) if constant_time_eq(token.trim().as_bytes(), expected.as_bytes()) => next.run(req).await,
+        _ => {
+            tracing::warn!(path = %req.uri().path(), "rejected request without a valid api token");
+            let body = ErrorBody { error: "missing or invalid bearer token".to_string(), ..Default::default() };
+            let mut resp = (StatusCode::UNAUTHORIZED, Json(body)).into_response();
+            resp.headers_mut().insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            resp
+        }
+    }
+}
+
+// Compare without returning early on the first differing byte, so response timing doesn't
+// reveal how much of a guessed token was right. Only the length can leak.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
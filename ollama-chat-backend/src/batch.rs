@@ -0,0 +1,75 @@
+use std::net::SocketAddr;
+
+use axum::{
+    extract::{ConnectInfo, State},
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+    Json,
+};
+use futures_util::{stream, StreamExt};
+use serde::Serialize;
+
+use crate::{bad_request, chat_reply, ratelimit, AppState, ChatReq, ChatResp, ErrorBody, JsonBody};
+
+// Most requests one batch may carry; bigger workloads can send several batches.
+const MAX_BATCH_REQUESTS: usize = 100;
+
+// One entry of the batch reply: the chat reply, or why that request failed.
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum BatchItem {
+    Reply(ChatResp),
+    Failed {
+        status: u16,
+        #[serde(flatten)]
+        error: ErrorBody,
+    },
+}
+
+// POST /api/chat/batch: independent non-streaming chats, answered in order. Each one goes
+// through the generation gate like any other chat, and a failure only fails its own entry.
+// Each request counts against RATE_LIMIT_PER_MINUTE.
+pub async fn chat_batch(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    JsonBody(reqs): JsonBody<Vec<ChatReq>>,
+) -> Response {
+    if reqs.is_empty() {
+        return bad_request("a batch needs at least one request".to_string()).into_response();
+    }
+    let max = match &state.rate_limiter {
+        Some(limiter) => MAX_BATCH_REQUESTS.min(limiter.per_minute() as usize),
+        None => MAX_BATCH_REQUESTS,
+    };
+    if reqs.len() > max {
+        let message = format!("a batch may carry at most {} requests, got {}", max, reqs.len());
+        return bad_request(message).into_response();
+    }
+    if let Some(resp) = ratelimit::charge_extra(&state, &headers, peer, reqs.len() - 1) {
+        return resp;
+    }
+    tracing::info!(requests = reqs.len(), "batch chat request");
+
+    // No more at once than there are generation slots, so one batch doesn't fill the queue and
+    // get its own tail turned away with 503s.
+    let concurrency = state.config.max_concurrent_generations;
+    let items = stream::iter(reqs)
+        .map(|req| {
+            let state = state.clone();
+            async move {
+                if req.stream == Some(true) {
+                    return Err(bad_request("stream is not supported in a batch".to_string()));
+                }
+                chat_reply(state, req).await
+            }
+        })
+        .buffered(concurrency)
+        .map(|result| match result {
+            Ok(Json(reply)) => BatchItem::Reply(reply),
+            Err(e) => BatchItem::Failed { status: e.status().as_u16(), error: e.into_body() },
+        })
+        .collect::<Vec<_>>()
+        .await;
+    Json(items).into_response()
+}
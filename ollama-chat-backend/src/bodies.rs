@@ -0,0 +1,55 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use crate::stream::StreamItem;
+use crate::Msg;
+
+// Chat content in the logs. It only ever goes out at debug level: in full when LOG_BODIES is
+// on, otherwise as a length and hash, which is enough to tell requests apart or spot a repeat
+// without keeping what anyone said.
+
+pub fn log_messages(log_bodies: bool, messages: &[Msg]) {
+    if !tracing::enabled!(tracing::Level::DEBUG) {
+        return;
+    }
+    for (index, m) in messages.iter().enumerate() {
+        tracing::debug!(index, role = %m.role, content = %shown(log_bodies, &m.content), "request message");
+    }
+}
+
+pub fn log_prompt(log_bodies: bool, prompt: &str) {
+    tracing::debug!(prompt = %shown(log_bodies, prompt), "request prompt");
+}
+
+pub fn log_reply(log_bodies: bool, content: &str) {
+    tracing::debug!(content = %shown(log_bodies, content), "reply");
+}
+
+// Wrap a stream encoder to log the assembled reply once the stream completes.
+pub fn log_stream<M, F>(log_bodies: bool, mut encode: F) -> impl FnMut(StreamItem) -> (Vec<M>, bool) + Send + 'static
+where
+    F: FnMut(StreamItem) -> (Vec<M>, bool) + Send + 'static,
+{
+    let enabled = tracing::enabled!(tracing::Level::DEBUG);
+    let mut reply = String::new();
+    move |item| {
+        if enabled {
+            match &item {
+                StreamItem::Delta(text) => reply.push_str(text),
+                StreamItem::Done(_) => log_reply(log_bodies, &std::mem::take(&mut reply)),
+                StreamItem::Thinking(_) | StreamItem::ToolCalls(_) | StreamItem::Error(_) | StreamItem::Shutdown => {}
+            }
+        }
+        encode(item)
+    }
+}
+
+// `text` as it may appear in the logs.
+pub fn shown(log_bodies: bool, text: &str) -> String {
+    if log_bodies {
+        return text.to_string();
+    }
+    let mut hasher = DefaultHasher::new();
+    text.hash(&mut hasher);
+    format!("[redacted: {} chars, hash {:016x}]", text.chars().count(), hasher.finish())
+}
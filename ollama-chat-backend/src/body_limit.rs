@@ -0,0 +1,28 @@
+use axum::{
+    extract::{DefaultBodyLimit, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+
+use crate::{config::Config, AppState, ErrorBody};
+
+pub fn layer(config: &Config) -> DefaultBodyLimit {
+    DefaultBodyLimit::max(config.max_body_bytes)
+}
+
+// axum answers an oversized body with a plain-text 413; swap in our JSON error so clients
+// learn what the limit is.
+pub async fn explain_too_large(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let resp = next.run(req).await;
+    if resp.status() != StatusCode::PAYLOAD_TOO_LARGE {
+        return resp;
+    }
+    tracing::warn!(limit = state.config.max_body_bytes, "rejected oversized request body");
+    let body = ErrorBody {
+        error: format!("request body exceeds the {} byte limit", state.config.max_body_bytes),
+        ..Default::default()
+    };
+    (StatusCode::PAYLOAD_TOO_LARGE, Json(body)).into_response()
+}
@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{AppState, BackendError};
+
+// Running streams that clients can stop by id, for when a closed EventSource doesn't make it
+// through a proxy promptly. Also how graceful shutdown ends the streams still open.
+#[derive(Default)]
+pub struct Cancellations {
+    tokens: Mutex<HashMap<String, CancellationToken>>,
+    // Parent of every stream's token, cancelled when the server shuts down.
+    shutdown: CancellationToken,
+}
+
+impl Cancellations {
+    pub fn register(self: &Arc<Self>) -> Registration {
+        let id = uuid::Uuid::new_v4().to_string();
+        let token = self.shutdown.child_token();
+        self.tokens.lock().unwrap().insert(id.clone(), token.clone());
+        Registration { id, token, registry: self.clone() }
+    }
+
+    // Stop every registered stream, each ending with a `shutdown` event.
+    pub fn shut_down(&self) {
+        let open = self.tokens.lock().unwrap().len();
+        tracing::info!(streams = open, "ending open streams for shutdown");
+        self.shutdown.cancel();
+    }
+
+    fn cancel(&self, id: &str) -> bool {
+        match self.tokens.lock().unwrap().remove(id) {
+            Some(token) => {
+                token.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+// A stream's entry in the registry; dropping it (when the stream ends) removes the entry.
+pub struct Registration {
+    pub id: String,
+    token: CancellationToken,
+    registry: Arc<Cancellations>,
+}
+
+impl Registration {
+    pub async fn cancelled(&self) {
+        self.token.cancelled().await
+    }
+
+    // Whether the stream was stopped by the server shutting down rather than by its client.
+    pub fn is_shutdown(&self) -> bool {
+        self.registry.shutdown.is_cancelled()
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.registry.tokens.lock().unwrap().remove(&self.id);
+    }
+}
+
+// Run a non-streaming generation, logging if it's abandoned. hyper drops a handler's future
+// when its client disconnects, and with it the in-flight call to Ollama (closing that
+// connection, which stops the generation) and the generation slot; this only says so.
+pub async fn log_if_abandoned<F: Future>(call: F) -> F::Output {
+    let guard = Abandoned { finished: false };
+    let output = call.await;
+    guard.finish();
+    output
+}
+
+struct Abandoned {
+    finished: bool,
+}
+
+impl Abandoned {
+    fn finish(mut self) {
+        self.finished = true;
+    }
+}
+
+impl Drop for Abandoned {
+    fn drop(&mut self) {
+        if !self.finished {
+            tracing::info!("client disconnected, aborting the ollama call");
+        }
+    }
+}
+
+// POST /api/cancel/:id: stop a running stream by the id from its `generation` event.
+pub async fn cancel(State(state): State<AppState>, Path(id): Path<String>) -> Result<StatusCode, BackendError> {
+    if state.cancellations.cancel(&id) {
+        tracing::info!(generation = %id, "generation cancelled by client");
+        return Ok(StatusCode::NO_CONTENT);
+    }
+    Err(BackendError::NotFound(format!("no running generation with id {:?}", id)))
+}
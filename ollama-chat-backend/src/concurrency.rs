@@ -0,0 +1,169 @@
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::{
+    extract::Request,
+    http::{header, HeaderValue, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use tokio::sync::{mpsc, watch, OwnedSemaphorePermit, Semaphore};
+
+use crate::BackendError;
+
+// What busy 503s suggest as Retry-After. Generations take seconds to minutes, so this is only
+// a polite pause before asking again.
+const BUSY_RETRY_AFTER_SECS: u64 = 5;
+
+// Caps how many generations run against Ollama at once. Requests beyond the cap wait in a
+// bounded queue; once the queue is full, or a request has waited too long, it gets a 503.
+pub struct GenerationGate {
+    permits: Arc<Semaphore>,
+    max_concurrent: usize,
+    queue: Mutex<Queue>,
+    // Bumped whenever someone leaves the queue, so waiters can recompute their position.
+    changed: watch::Sender<()>,
+    max_queue: usize,
+    max_wait: Duration,
+}
+
+// Tickets of the requests waiting for a slot. The semaphore serves waiters in order, so a
+// request's place in line is the number of tickets at or before its own.
+#[derive(Default)]
+struct Queue {
+    next_ticket: u64,
+    waiting: BTreeSet<u64>,
+}
+
+// How a request got in: straight to a slot, or into the queue.
+pub enum Admission {
+    Ready(OwnedSemaphorePermit),
+    Queued(Waiter),
+}
+
+// A place in the queue. Dropping it gives the place up.
+pub struct Waiter {
+    gate: Arc<GenerationGate>,
+    ticket: u64,
+}
+
+impl GenerationGate {
+    pub fn new(max_concurrent: usize, max_queue: usize, max_wait: Duration) -> GenerationGate {
+        GenerationGate {
+            permits: Arc::new(Semaphore::new(max_concurrent)),
+            max_concurrent,
+            queue: Mutex::new(Queue::default()),
+            changed: watch::channel(()).0,
+            max_queue,
+            max_wait,
+        }
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.max_concurrent.saturating_sub(self.permits.available_permits())
+    }
+
+    pub fn queued(&self) -> usize {
+        self.queue.lock().unwrap().waiting.len()
+    }
+
+    // Wait for a slot. The generation may run for as long as the returned permit is held.
+    pub async fn acquire(self: &Arc<Self>) -> Result<OwnedSemaphorePermit, BackendError> {
+        match self.admit()? {
+            Admission::Ready(permit) => Ok(permit),
+            Admission::Queued(waiter) => waiter.wait(|_| {}).await,
+        }
+    }
+
+    // Take a free slot, or a place in the queue if there is none; 503 if the queue is full.
+    pub fn admit(self: &Arc<Self>) -> Result<Admission, BackendError> {
+        if let Ok(permit) = self.permits.clone().try_acquire_owned() {
+            return Ok(Admission::Ready(permit));
+        }
+
+        let mut queue = self.queue.lock().unwrap();
+        if queue.waiting.len() >= self.max_queue {
+            tracing::warn!("generation queue full, rejecting request");
+            return Err(BackendError::Busy("server is busy, generation queue is full".to_string()));
+        }
+        let ticket = queue.next_ticket;
+        queue.next_ticket += 1;
+        queue.waiting.insert(ticket);
+        Ok(Admission::Queued(Waiter { gate: self.clone(), ticket }))
+    }
+}
+
+impl Waiter {
+    // Wait for a slot, calling `on_position` with this request's place in line (1 is next)
+    // at the start and whenever it moves up.
+    pub async fn wait(self, mut on_position: impl FnMut(usize)) -> Result<OwnedSemaphorePermit, BackendError> {
+        let gate = self.gate.clone();
+        let mut changes = gate.changed.subscribe();
+        let waiting = async {
+            let mut acquire = std::pin::pin!(gate.permits.clone().acquire_owned());
+            let mut last = 0;
+            loop {
+                let position = self.position();
+                if position != last {
+                    on_position(position);
+                    last = position;
+                }
+                tokio::select! {
+                    permit = &mut acquire => return permit,
+                    _ = changes.changed() => {}
+                }
+            }
+        };
+        let result = tokio::time::timeout(gate.max_wait, waiting).await;
+        drop(self);
+
+        match result {
+            Ok(Ok(permit)) => Ok(permit),
+            Ok(Err(_)) => Err(BackendError::Busy("server is shutting down".to_string())),
+            Err(_) => {
+                tracing::warn!("gave up waiting for a generation slot");
+                Err(BackendError::Busy(format!(
+                    "server is busy, no generation slot freed up within {}s",
+                    gate.max_wait.as_secs()
+                )))
+            }
+        }
+    }
+
+    // `wait`, sending `queued(position)` on `tx` as the place in line changes. None if the
+    // client goes away first, which gives the place up.
+    pub async fn wait_reporting<M>(
+        self,
+        tx: &mpsc::Sender<M>,
+        queued: impl Fn(usize) -> M,
+    ) -> Option<Result<OwnedSemaphorePermit, BackendError>> {
+        tokio::select! {
+            permit = self.wait(|position| {
+                let _ = tx.try_send(queued(position));
+            }) => Some(permit),
+            _ = tx.closed() => None,
+        }
+    }
+
+    fn position(&self) -> usize {
+        self.gate.queue.lock().unwrap().waiting.range(..=self.ticket).count()
+    }
+}
+
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        self.gate.queue.lock().unwrap().waiting.remove(&self.ticket);
+        self.gate.changed.send_modify(|_| {});
+    }
+}
+
+// Suggest when to come back on the generation routes' 503s, which only come from a full
+// queue or a wait that ran out.
+pub async fn retry_after(req: Request, next: Next) -> Response {
+    let mut resp = next.run(req).await;
+    if resp.status() == StatusCode::SERVICE_UNAVAILABLE && !resp.headers().contains_key(header::RETRY_AFTER) {
+        resp.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(BUSY_RETRY_AFTER_SECS));
+    }
+    resp
+}
@@ -0,0 +1,401 @@
+use std::collections::HashMap;
+use std::env;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::model::{self, ModelAliases};
+use crate::ollama::OllamaOptions;
+use crate::presets::ModelPresets;
+use crate::system_prompt;
+use crate::tls::{self, TlsPaths};
+
+const DEFAULT_OLLAMA_HOST: &str = "http://127.0.0.1:11434";
+const DEFAULT_BIND_ADDR: &str = "127.0.0.1";
+const DEFAULT_PORT: u16 = 8080;
+const DEFAULT_TIMEOUT_SECS: u64 = 120;
+const DEFAULT_STREAM_IDLE_TIMEOUT_SECS: u64 = 60;
+const DEFAULT_SHUTDOWN_GRACE_SECS: u64 = 10;
+const DEFAULT_RATE_LIMIT_PER_MINUTE: u64 = 60;
+const DEFAULT_MAX_CONCURRENT_GENERATIONS: u64 = 2;
+const DEFAULT_GENERATION_QUEUE_SIZE: u64 = 16;
+const DEFAULT_GENERATION_QUEUE_TIMEOUT_SECS: u64 = 60;
+const DEFAULT_RETRY_ATTEMPTS: u64 = 3;
+const DEFAULT_RETRY_BASE_DELAY_MS: u64 = 250;
+const DEFAULT_MAX_BODY_BYTES: u64 = 4 * 1024 * 1024;
+const DEFAULT_SSE_KEEPALIVE_SECS: u64 = 15;
+const DEFAULT_SSE_RETRY_MS: u64 = 2000;
+const DEFAULT_MAX_MESSAGES: u64 = 1000;
+const DEFAULT_MAX_MESSAGE_CHARS: u64 = 100_000;
+const DEFAULT_RESPONSE_CACHE_TTL_SECS: u64 = 300;
+const DEFAULT_SLOW_REQUEST_MS: u64 = 10_000;
+const DEFAULT_IDEMPOTENCY_TTL_SECS: u64 = 300;
+const DEFAULT_ALIASES_FILE: &str = "aliases.toml";
+const DEFAULT_EMBED_MODEL: &str = "nomic-embed-text";
+// The Vite dev server, used when no allowlist is configured outside dev mode.
+const DEFAULT_ALLOWED_ORIGINS: &[&str] = &["http://localhost:5173", "http://127.0.0.1:5173"];
+
+// Runtime configuration, read once at startup from the environment.
+#[derive(Clone, Debug)]
+pub struct Config {
+    // Address the HTTP server listens on.
+    pub bind_addr: SocketAddr,
+    // Serve HTTPS with this certificate and key; `None` for plain HTTP.
+    pub tls: Option<TlsPaths>,
+    // Base URLs of the Ollama servers, without a trailing slash; usually just the one.
+    pub ollama_hosts: Vec<String>,
+    // Path Ollama's API is mounted under behind a gateway, e.g. "/ollama"; empty when it isn't.
+    pub ollama_path_prefix: String,
+    // Total time allowed for a non-streaming chat call, including reading the body.
+    pub request_timeout: Duration,
+    // Longest a stream may go without a byte from Ollama before it's treated as dead.
+    pub stream_idle_timeout: Duration,
+    pub aliases: ModelAliases,
+    // Default options per model, under whatever a request sets itself.
+    pub presets: ModelPresets,
+    // Model used by the chat and generate routes when the request doesn't name one.
+    pub default_model: String,
+    // Origins allowed to call the API cross-origin; `None` allows any (dev mode only).
+    pub allowed_origins: Option<Vec<String>>,
+    // The only models requests may use, after aliases; `None` allows any.
+    pub allowed_models: Option<Vec<String>>,
+    // Model used by /api/embeddings when the request doesn't name one.
+    pub embed_model: String,
+    // Models to fall back to, in order, when the requested one fails to load.
+    pub model_fallbacks: Vec<String>,
+    // How long in-flight requests get to finish after a shutdown signal.
+    pub shutdown_grace: Duration,
+    // keep_alive sent to Ollama when a request doesn't set its own.
+    pub keep_alive: Option<String>,
+    // Options sent to Ollama where neither the request nor the model's preset sets them:
+    // OLLAMA_NUM_CTX, DEFAULT_TEMPERATURE, DEFAULT_TOP_P and DEFAULT_NUM_PREDICT.
+    pub default_options: OllamaOptions,
+    // Generation requests allowed per client IP per minute; 0 disables limiting.
+    pub rate_limit_per_minute: u32,
+    // Take the client IP from X-Forwarded-For (only safe behind a proxy that sets it).
+    pub trust_proxy: bool,
+    // Serve canned chat replies instead of calling Ollama, for frontend work without a GPU.
+    pub mock_mode: bool,
+    // Log chat content in full at debug level, instead of only its length and a hash.
+    pub log_bodies: bool,
+    // Send SSE token deltas as raw text, as before they were JSON-encoded, for older clients.
+    pub sse_text_deltas: bool,
+    // Tag (e.g. "think") whose `<think>...</think>` spans in replies are reasoning, sent apart
+    // from the answer; `None` leaves replies as the model wrote them.
+    pub think_tag: Option<String>,
+    // Bearer token required on the API routes; `None` leaves them open.
+    pub api_token: Option<String>,
+    // Generations allowed to run against Ollama at once, across all routes.
+    pub max_concurrent_generations: usize,
+    // Requests allowed to wait for a slot before new ones are turned away with 503.
+    pub generation_queue_size: usize,
+    // Longest a queued request waits for a slot before giving up with 503.
+    pub generation_queue_timeout: Duration,
+    // Attempts (including the first) for non-streaming calls that fail to connect or get a 503.
+    pub retry_attempts: u32,
+    // Delay before the first retry; doubles on each further attempt.
+    pub retry_base_delay: Duration,
+    // Largest request body accepted; bigger ones get a 413 before being parsed.
+    pub max_body_bytes: usize,
+    // Hard cap on generated tokens per reply, whatever the client asks for; `None` when unset.
+    pub max_output_tokens: Option<u32>,
+    // Default token budget for chat conversations; the oldest turns are dropped to fit.
+    pub max_context_tokens: Option<u32>,
+    // Most messages a chat request may carry; `None` for no limit.
+    pub max_messages: Option<usize>,
+    // Longest content a single chat message may have, in characters; `None` for no limit.
+    pub max_message_chars: Option<usize>,
+    // SQLite database for stored conversations; `None` disables those routes.
+    pub database_url: Option<String>,
+    // Interval between SSE comment pings on quiet streams; `None` when set to 0.
+    pub sse_keep_alive: Option<Duration>,
+    // Reconnect delay sent as `retry:` at the start of each SSE stream; `None` when set to 0.
+    pub sse_retry: Option<Duration>,
+    // System prompt put in front of every chat, with placeholders filled in per request.
+    pub system_prompt_template: Option<String>,
+    // Deterministic chat replies kept for reuse; 0 turns the cache off.
+    pub response_cache_size: usize,
+    // How long a cached reply may be served.
+    pub response_cache_ttl: Duration,
+    // Chats taking longer than this are logged as slow; `None` when set to 0.
+    pub slow_request: Option<Duration>,
+    // How long a reply is kept for its Idempotency-Key; `None` when set to 0.
+    pub idempotency_ttl: Option<Duration>,
+    // How long a chat stream waits for its client to reconnect with Last-Event-ID; `None` when
+    // set to 0, which stops a generation as soon as its client goes.
+    pub stream_resume: Option<Duration>,
+    // Roles renamed before validation, e.g. "human" to "user", for clients with their own names.
+    pub role_map: HashMap<String, String>,
+}
+
+impl Config {
+    pub fn from_env() -> Result<Config, String> {
+        // OLLAMA_HOSTS spreads requests over several instances; OLLAMA_HOST names just one.
+        let ollama_hosts = match env_list("OLLAMA_HOSTS") {
+            Some(hosts) => hosts.into_iter().map(|h| parse_ollama_host("OLLAMA_HOSTS", Some(h))).collect::<Result<_, _>>()?,
+            None => vec![parse_ollama_host("OLLAMA_HOST", env::var("OLLAMA_HOST").ok())?],
+        };
+        let request_timeout = match env_u64("OLLAMA_TIMEOUT_SECS", DEFAULT_TIMEOUT_SECS)? {
+            // A zero timeout would fail every call at once rather than mean "no timeout".
+            0 => return Err("OLLAMA_TIMEOUT_SECS must be at least 1".to_string()),
+            n => Duration::from_secs(n),
+        };
+        // An explicitly configured alias file must exist; the default one is optional.
+        let aliases = match env::var("MODEL_ALIASES_FILE") {
+            Ok(path) if !path.trim().is_empty() => ModelAliases::load(&PathBuf::from(path.trim()), true)?,
+            _ => ModelAliases::load(&PathBuf::from(DEFAULT_ALIASES_FILE), false)?,
+        };
+        let role_map = parse_role_map(env_list("ROLE_MAP"))?;
+        let allowed_origins = parse_allowed_origins(env_list("ALLOWED_ORIGINS"), env_bool("DEV_MODE")?)?;
+        let embed_model = env_string("EMBED_MODEL").unwrap_or_else(|| DEFAULT_EMBED_MODEL.to_string());
+        let shutdown_grace = Duration::from_secs(env_u64("SHUTDOWN_GRACE_SECS", DEFAULT_SHUTDOWN_GRACE_SECS)?);
+        let system_prompt_template = env_string("SYSTEM_PROMPT_TEMPLATE");
+        if let Some(template) = &system_prompt_template {
+            system_prompt::validate(template)?;
+        }
+        Ok(Config {
+            bind_addr: parse_bind_addr(env_string("BIND_ADDR"), env_string("PORT"))?,
+            tls: tls::paths(env_string("TLS_CERT"), env_string("TLS_KEY"))?,
+            ollama_hosts,
+            ollama_path_prefix: parse_path_prefix(env_string("OLLAMA_PATH_PREFIX"))?,
+            request_timeout,
+            stream_idle_timeout: match env_u64("STREAM_IDLE_TIMEOUT_SECS", DEFAULT_STREAM_IDLE_TIMEOUT_SECS)? {
+                0 => return Err("STREAM_IDLE_TIMEOUT_SECS must be at least 1".to_string()),
+                n => Duration::from_secs(n),
+            },
+            aliases,
+            presets: match env_string("MODEL_PRESETS_FILE") {
+                Some(path) => ModelPresets::load(&PathBuf::from(path))?,
+                None => ModelPresets::default(),
+            },
+            default_model: env_string("DEFAULT_MODEL").unwrap_or_else(|| model::DEFAULT_MODEL.to_string()),
+            allowed_origins,
+            role_map,
+            allowed_models: env_list("ALLOWED_MODELS"),
+            embed_model,
+            model_fallbacks: env_list("MODEL_FALLBACKS").unwrap_or_default(),
+            shutdown_grace,
+            keep_alive: env_string("OLLAMA_KEEP_ALIVE"),
+            default_options: OllamaOptions {
+                num_ctx: env_parse("OLLAMA_NUM_CTX", "a positive integer")?,
+                temperature: env_parse("DEFAULT_TEMPERATURE", "a number")?,
+                top_p: env_parse("DEFAULT_TOP_P", "a number")?,
+                num_predict: env_parse("DEFAULT_NUM_PREDICT", "an integer")?,
+                ..Default::default()
+            },
+            rate_limit_per_minute: env_u64("RATE_LIMIT_PER_MINUTE", DEFAULT_RATE_LIMIT_PER_MINUTE)?
+                .try_into()
+                .map_err(|_| "RATE_LIMIT_PER_MINUTE is too large".to_string())?,
+            trust_proxy: env_bool("TRUST_PROXY")?,
+            mock_mode: env_bool("MOCK_MODE")?,
+            log_bodies: env_bool("LOG_BODIES")?,
+            sse_text_deltas: env_bool("SSE_TEXT_DELTAS")?,
+            think_tag: env_string("THINK_TAG"),
+            api_token: env_string("API_TOKEN"),
+            max_concurrent_generations: match env_u64("MAX_CONCURRENT_GENERATIONS", DEFAULT_MAX_CONCURRENT_GENERATIONS)? {
+                0 => return Err("MAX_CONCURRENT_GENERATIONS must be at least 1".to_string()),
+                n => n as usize,
+            },
+            generation_queue_size: env_u64("GENERATION_QUEUE_SIZE", DEFAULT_GENERATION_QUEUE_SIZE)? as usize,
+            generation_queue_timeout: Duration::from_secs(env_u64(
+                "GENERATION_QUEUE_TIMEOUT_SECS",
+                DEFAULT_GENERATION_QUEUE_TIMEOUT_SECS,
+            )?),
+            retry_attempts: env_u64("OLLAMA_RETRY_ATTEMPTS", DEFAULT_RETRY_ATTEMPTS)?.min(u32::MAX as u64) as u32,
+            retry_base_delay: Duration::from_millis(env_u64("OLLAMA_RETRY_BASE_DELAY_MS", DEFAULT_RETRY_BASE_DELAY_MS)?),
+            database_url: env_string("DATABASE_URL"),
+            max_context_tokens: match env_u64("MAX_CONTEXT_TOKENS", 0)? {
+                0 => None,
+                n => Some(u32::try_from(n).map_err(|_| "MAX_CONTEXT_TOKENS is too large".to_string())?),
+            },
+            max_messages: match env_u64("MAX_MESSAGES", DEFAULT_MAX_MESSAGES)? {
+                0 => None,
+                n => Some(usize::try_from(n).map_err(|_| "MAX_MESSAGES is too large".to_string())?),
+            },
+            max_message_chars: match env_u64("MAX_MESSAGE_CHARS", DEFAULT_MAX_MESSAGE_CHARS)? {
+                0 => None,
+                n => Some(usize::try_from(n).map_err(|_| "MAX_MESSAGE_CHARS is too large".to_string())?),
+            },
+            max_output_tokens: match env_u64("MAX_OUTPUT_TOKENS", 0)? {
+                0 => None,
+                n if n > i32::MAX as u64 => return Err("MAX_OUTPUT_TOKENS is too large".to_string()),
+                n => Some(n as u32),
+            },
+            max_body_bytes: env_u64("MAX_BODY_BYTES", DEFAULT_MAX_BODY_BYTES)?
+                .try_into()
+                .map_err(|_| "MAX_BODY_BYTES is too large".to_string())?,
+            sse_keep_alive: match env_u64("SSE_KEEPALIVE_SECS", DEFAULT_SSE_KEEPALIVE_SECS)? {
+                0 => None,
+                n => Some(Duration::from_secs(n)),
+            },
+            sse_retry: match env_u64("SSE_RETRY_MS", DEFAULT_SSE_RETRY_MS)? {
+                0 => None,
+                n => Some(Duration::from_millis(n)),
+            },
+            system_prompt_template,
+            response_cache_size: env_u64("RESPONSE_CACHE_SIZE", 0)?
+                .try_into()
+                .map_err(|_| "RESPONSE_CACHE_SIZE is too large".to_string())?,
+            response_cache_ttl: Duration::from_secs(env_u64("RESPONSE_CACHE_TTL_SECS", DEFAULT_RESPONSE_CACHE_TTL_SECS)?),
+            slow_request: match env_u64("SLOW_REQUEST_MS", DEFAULT_SLOW_REQUEST_MS)? {
+                0 => None,
+                n => Some(Duration::from_millis(n)),
+            },
+            idempotency_ttl: match env_u64("IDEMPOTENCY_TTL_SECS", DEFAULT_IDEMPOTENCY_TTL_SECS)? {
+                0 => None,
+                n => Some(Duration::from_secs(n)),
+            },
+            stream_resume: match env_u64("STREAM_RESUME_SECS", 0)? {
+                0 => None,
+                n => Some(Duration::from_secs(n)),
+            },
+        })
+    }
+}
+
+fn parse_bind_addr(addr: Option<String>, port: Option<String>) -> Result<SocketAddr, String> {
+    let addr = addr.unwrap_or_else(|| DEFAULT_BIND_ADDR.to_string());
+    let ip: IpAddr = addr
+        .parse()
+        .map_err(|_| format!("BIND_ADDR must be an IP address such as 0.0.0.0 or ::1, got {:?}", addr))?;
+    let port = match port {
+        Some(p) => p.parse().map_err(|_| format!("PORT must be a number from 0 to 65535, got {:?}", p))?,
+        None => DEFAULT_PORT,
+    };
+    Ok(SocketAddr::new(ip, port))
+}
+
+fn parse_ollama_host(name: &str, raw: Option<String>) -> Result<String, String> {
+    let raw = match raw {
+        Some(s) if !s.trim().is_empty() => s.trim().to_string(),
+        _ => return Ok(DEFAULT_OLLAMA_HOST.to_string()),
+    };
+
+    let url = reqwest::Url::parse(&raw)
+        .map_err(|e| format!("{} {:?} is not a valid URL: {}", name, raw, e))?;
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(format!(
+            "{} {:?} must use http or https, got {:?}",
+            name,
+            raw,
+            url.scheme()
+        ));
+    }
+    if url.host_str().is_none() {
+        return Err(format!("{} {:?} has no host", name, raw));
+    }
+
+    Ok(raw.trim_end_matches('/').to_string())
+}
+
+// "ollama", "/ollama/" and "/ollama" all mean "/ollama".
+fn parse_path_prefix(raw: Option<String>) -> Result<String, String> {
+    let Some(raw) = raw else {
+        return Ok(String::new());
+    };
+    let trimmed = raw.trim_matches('/');
+    if trimmed.is_empty() {
+        return Ok(String::new());
+    }
+    if trimmed.contains(['?', '#']) || trimmed.contains(char::is_whitespace) {
+        return Err(format!("OLLAMA_PATH_PREFIX {:?} must be a plain path like /ollama", raw));
+    }
+    Ok(format!("/{}", trimmed))
+}
+
+// "human=user,ai=assistant": each entry names a client's role and the one it stands for.
+fn parse_role_map(list: Option<Vec<String>>) -> Result<HashMap<String, String>, String> {
+    let mut map = HashMap::new();
+    for entry in list.unwrap_or_default() {
+        let Some((from, to)) = entry.split_once('=').map(|(from, to)| (from.trim(), to.trim())) else {
+            return Err(format!("ROLE_MAP entry {:?} must look like human=user", entry));
+        };
+        if from.is_empty() || !crate::VALID_ROLES.contains(&to) {
+            return Err(format!(
+                "ROLE_MAP entry {:?} must map a role to one of {}",
+                entry,
+                crate::VALID_ROLES.join(", ")
+            ));
+        }
+        map.insert(from.to_string(), to.to_string());
+    }
+    Ok(map)
+}
+
+fn parse_allowed_origins(list: Option<Vec<String>>, dev_mode: bool) -> Result<Option<Vec<String>>, String> {
+    let origins = match list {
+        Some(origins) => origins,
+        None if dev_mode => return Ok(None),
+        None => DEFAULT_ALLOWED_ORIGINS.iter().map(|s| s.to_string()).collect(),
+    };
+    for origin in &origins {
+        let url = reqwest::Url::parse(origin)
+            .map_err(|e| format!("ALLOWED_ORIGINS entry {:?} is not a valid origin: {}", origin, e))?;
+        if url.host_str().is_none() || url.path() != "/" {
+            return Err(format!(
+                "ALLOWED_ORIGINS entry {:?} must be scheme://host[:port] with no path",
+                origin
+            ));
+        }
+    }
+    // Browsers send the ASCII serialization in the Origin header, so compare against that.
+    Ok(Some(
+        origins
+            .iter()
+            .filter_map(|o| reqwest::Url::parse(o).ok())
+            .map(|u| u.origin().ascii_serialization())
+            .collect(),
+    ))
+}
+
+// Read a string, treating unset and blank the same.
+fn env_string(name: &str) -> Option<String> {
+    env::var(name).ok().map(|v| v.trim().to_string()).filter(|v| !v.is_empty())
+}
+
+// Read a comma-separated list, dropping blank entries. Unset or empty means `None`.
+fn env_list(name: &str) -> Option<Vec<String>> {
+    let items: Vec<String> = env::var(name)
+        .ok()?
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect();
+    if items.is_empty() {
+        None
+    } else {
+        Some(items)
+    }
+}
+
+// Read an on/off flag; unset means off.
+fn env_bool(name: &str) -> Result<bool, String> {
+    match env::var(name) {
+        Ok(v) => match v.trim().to_ascii_lowercase().as_str() {
+            "" | "0" | "false" | "no" | "off" => Ok(false),
+            "1" | "true" | "yes" | "on" => Ok(true),
+            _ => Err(format!("{} must be true or false, got {:?}", name, v)),
+        },
+        Err(_) => Ok(false),
+    }
+}
+
+// Read an optional value of any parseable type, e.g. a float; unset or blank means `None`.
+fn env_parse<T: FromStr>(name: &str, what: &str) -> Result<Option<T>, String> {
+    match env_string(name) {
+        Some(v) => v.parse().map(Some).map_err(|_| format!("{} must be {}, got {:?}", name, what, v)),
+        None => Ok(None),
+    }
+}
+
+// Read a non-negative integer from the environment, using `default` when unset or blank.
+fn env_u64(name: &str, default: u64) -> Result<u64, String> {
+    match env::var(name) {
+        Ok(v) if !v.trim().is_empty() => v
+            .trim()
+            .parse()
+            .map_err(|_| format!("{} must be a non-negative integer, got {:?}", name, v)),
+        _ => Ok(default),
+    }
+}
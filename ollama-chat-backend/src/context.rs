@@ -0,0 +1,76 @@
+use crate::{Msg, CHARS_PER_TOKEN};
+
+// Drop the oldest turns until the conversation, plus `reserved_chars` sent alongside it (the
+// `system` field), is estimated to fit `budget` tokens. System messages and the final message
+// are always kept, and a user message goes together with the assistant replies after it, so
+// the model never sees an answer without its question. Returns how many messages were dropped;
+// if the kept messages alone are over budget, that's as far as it goes.
+pub fn trim(messages: &mut Vec<Msg>, budget: usize, reserved_chars: usize) -> usize {
+    let budget_chars = budget.saturating_mul(CHARS_PER_TOKEN);
+    let mut chars = reserved_chars + messages.iter().map(|m| m.content.chars().count()).sum::<usize>();
+    let mut dropped = 0;
+    while chars > budget_chars {
+        let Some(start) = messages.iter().position(|m| m.role != "system") else {
+            break;
+        };
+        let last = messages.len() - 1;
+        if start >= last {
+            break;
+        }
+        let mut end = start + 1;
+        while end < last && messages[end].role == "assistant" {
+            end += 1;
+        }
+        for m in messages.drain(start..end) {
+            chars -= m.content.chars().count();
+            dropped += 1;
+        }
+    }
+    dropped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(role: &str, content: &str) -> Msg {
+        Msg { role: role.to_string(), content: content.to_string(), images: None, tool_calls: None }
+    }
+
+    fn roles_and_content(messages: &[Msg]) -> Vec<(&str, &str)> {
+        messages.iter().map(|m| (m.role.as_str(), m.content.as_str())).collect()
+    }
+
+    #[test]
+    fn drops_oldest_turns_and_keeps_the_system_message() {
+        // Each message is about 2 tokens.
+        let mut messages = vec![
+            msg("system", "be brief"),
+            msg("user", "question1"),
+            msg("assistant", "answer11"),
+            msg("user", "question2"),
+            msg("assistant", "answer22"),
+            msg("user", "question3"),
+        ];
+        assert_eq!(trim(&mut messages, 9, 0), 2);
+        assert_eq!(
+            roles_and_content(&messages),
+            [("system", "be brief"), ("user", "question2"), ("assistant", "answer22"), ("user", "question3")]
+        );
+    }
+
+    #[test]
+    fn leaves_a_conversation_within_budget_alone() {
+        let mut messages = vec![msg("user", "hi"), msg("assistant", "hello"), msg("user", "bye")];
+        assert_eq!(trim(&mut messages, 100, 0), 0);
+        assert_eq!(messages.len(), 3);
+    }
+
+    #[test]
+    fn always_keeps_the_last_message() {
+        let long = "x".repeat(400);
+        let mut messages = vec![msg("user", "old"), msg("assistant", "reply"), msg("user", &long)];
+        assert_eq!(trim(&mut messages, 10, 0), 2);
+        assert_eq!(roles_and_content(&messages), [("user", long.as_str())]);
+    }
+}
@@ -0,0 +1,147 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::store::{Conversation, Store, StoredMessage};
+use crate::stream::StreamItem;
+use crate::{bad_request, AppState, BackendError, JsonBody, Msg, VALID_ROLES};
+
+const DEFAULT_TITLE: &str = "New conversation";
+
+#[derive(Deserialize)]
+pub struct CreateReq {
+    title: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct AppendReq {
+    messages: Vec<Msg>,
+}
+
+#[derive(Serialize)]
+pub struct ConversationDetail {
+    #[serde(flatten)]
+    conversation: Conversation,
+    messages: Vec<StoredMessage>,
+}
+
+// POST /api/conversations
+pub async fn create(
+    State(state): State<AppState>,
+    JsonBody(req): JsonBody<CreateReq>,
+) -> Result<(StatusCode, Json<Conversation>), BackendError> {
+    let title = req.title.map(|t| t.trim().to_string()).filter(|t| !t.is_empty());
+    let title = title.unwrap_or_else(|| DEFAULT_TITLE.to_string());
+    let conversation = store(&state)?.create_conversation(title).await?;
+    Ok((StatusCode::CREATED, Json(conversation)))
+}
+
+// GET /api/conversations
+pub async fn list(State(state): State<AppState>) -> Result<Json<Vec<Conversation>>, BackendError> {
+    Ok(Json(store(&state)?.list_conversations().await?))
+}
+
+// GET /api/conversations/:id, with its messages oldest first.
+pub async fn get(State(state): State<AppState>, Path(id): Path<String>) -> Result<Json<ConversationDetail>, BackendError> {
+    match store(&state)?.get_conversation(id.clone()).await? {
+        Some((conversation, messages)) => Ok(Json(ConversationDetail { conversation, messages })),
+        None => Err(not_found(&id)),
+    }
+}
+
+// POST /api/conversations/:id/messages
+pub async fn append(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    JsonBody(req): JsonBody<AppendReq>,
+) -> Result<StatusCode, BackendError> {
+    if req.messages.is_empty() {
+        return Err(bad_request("messages must not be empty".to_string()));
+    }
+    if let Some((i, m)) = req.messages.iter().enumerate().find(|(_, m)| !VALID_ROLES.contains(&m.role.as_str())) {
+        return Err(bad_request(format!(
+            "messages[{}].role must be one of {}, got {:?}",
+            i,
+            VALID_ROLES.join(", "),
+            m.role
+        )));
+    }
+    let messages = req.messages.into_iter().map(|m| (m.role, m.content)).collect();
+    match store(&state)?.append(id.clone(), messages).await? {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Err(not_found(&id)),
+    }
+}
+
+// Check up front that a chat request's `conversation_id` can be saved to, so a typo fails
+// before spending a generation on it.
+pub async fn check(state: &AppState, conversation_id: Option<&str>) -> Result<(), BackendError> {
+    let Some(id) = conversation_id else {
+        return Ok(());
+    };
+    match store(state)?.exists(id.to_string()).await? {
+        true => Ok(()),
+        false => Err(not_found(id)),
+    }
+}
+
+// Save a finished exchange: the user's latest message, if it ended the request, and the reply.
+// The chat already succeeded by now, so a failure here is only logged.
+pub async fn record(state: &AppState, conversation_id: &str, messages: &[Msg], reply: String) {
+    let Some(store) = &state.store else {
+        return;
+    };
+    let mut rows = Vec::with_capacity(2);
+    if let Some(last) = messages.last().filter(|m| m.role == "user") {
+        rows.push((last.role.clone(), last.content.clone()));
+    }
+    rows.push(("assistant".to_string(), reply));
+    match store.append(conversation_id.to_string(), rows).await {
+        Ok(true) => {}
+        Ok(false) => tracing::warn!(conversation = %conversation_id, "conversation was deleted before the reply was saved"),
+        Err(e) => tracing::error!(conversation = %conversation_id, error = %e.message(), "failed to save reply"),
+    }
+}
+
+// Wrap a stream encoder so that, when `conversation_id` is set, the reply is saved once the
+// stream completes. Streams that fail or get cancelled save nothing.
+pub fn record_stream<M, F>(
+    state: AppState,
+    conversation_id: Option<String>,
+    messages: Vec<Msg>,
+    mut encode: F,
+) -> impl FnMut(StreamItem) -> (Vec<M>, bool) + Send + 'static
+where
+    F: FnMut(StreamItem) -> (Vec<M>, bool) + Send + 'static,
+{
+    let mut reply = String::new();
+    move |item| {
+        if let Some(id) = &conversation_id {
+            match &item {
+                StreamItem::Delta(text) => reply.push_str(text),
+                StreamItem::Done(_) => {
+                    let (state, id, messages) = (state.clone(), id.clone(), messages.clone());
+                    let reply = std::mem::take(&mut reply);
+                    tokio::spawn(async move { record(&state, &id, &messages, reply).await });
+                }
+                StreamItem::Thinking(_) | StreamItem::ToolCalls(_) | StreamItem::Error(_) | StreamItem::Shutdown => {}
+            }
+        }
+        encode(item)
+    }
+}
+
+fn store(state: &AppState) -> Result<&Arc<Store>, BackendError> {
+    state.store.as_ref().ok_or_else(|| {
+        BackendError::Unsupported("conversation storage is not configured (set DATABASE_URL)".to_string())
+    })
+}
+
+fn not_found(id: &str) -> BackendError {
+    BackendError::NotFound(format!("no conversation with id {:?}", id))
+}
@@ -0,0 +1,59 @@
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderName, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+
+use crate::{config::Config, idempotency, request_id, resume, AppState, ErrorBody, TIMING_HEADERS};
+
+// Request headers a browser may send cross-origin beyond the always-safe ones.
+const ALLOWED_HEADERS: [HeaderName; 7] = [
+    header::CONTENT_TYPE,
+    header::AUTHORIZATION,
+    header::ACCEPT,
+    HeaderName::from_static(request_id::HEADER),
+    HeaderName::from_static("x-debug"),
+    HeaderName::from_static(idempotency::HEADER),
+    HeaderName::from_static(resume::HEADER),
+];
+
+pub fn layer(config: &Config) -> CorsLayer {
+    // Response headers a cross-origin page may read.
+    let exposed = [request_id::HEADER, header::RETRY_AFTER.as_str(), idempotency::REPLAYED_HEADER].into_iter().chain(TIMING_HEADERS);
+    let cors = CorsLayer::new().expose_headers(exposed.map(HeaderName::from_static).collect::<Vec<_>>());
+    match &config.allowed_origins {
+        // Browsers refuse credentials with wildcards, so dev mode stays cookie-free.
+        None => cors.allow_origin(Any).allow_methods(Any).allow_headers(Any),
+        // Named origins may send cookies and auth headers, which rules out wildcards for the
+        // methods and headers too.
+        Some(origins) => {
+            let origins = origins.iter().filter_map(|o| HeaderValue::from_str(o).ok());
+            cors.allow_origin(AllowOrigin::list(origins))
+                .allow_methods([Method::GET, Method::POST, Method::DELETE])
+                .allow_headers(ALLOWED_HEADERS)
+                .allow_credentials(true)
+        }
+    }
+}
+
+// CorsLayer simply omits the allow headers for unknown origins; answer their preflights with an
+// explicit 403 instead so the refusal is visible server-side too.
+pub async fn reject_disallowed_preflight(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let is_preflight = req.method() == Method::OPTIONS
+        && req.headers().contains_key(header::ACCESS_CONTROL_REQUEST_METHOD);
+    if let (true, Some(allowed), Some(origin)) = (
+        is_preflight,
+        &state.config.allowed_origins,
+        req.headers().get(header::ORIGIN),
+    ) {
+        if !allowed.iter().any(|a| a.as_bytes() == origin.as_bytes()) {
+            tracing::warn!(?origin, "rejected preflight from disallowed origin");
+            let body = ErrorBody { error: "origin not allowed".to_string(), ..Default::default() };
+            return (StatusCode::FORBIDDEN, Json(body)).into_response();
+        }
+    }
+    next.run(req).await
+}
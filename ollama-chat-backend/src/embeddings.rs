@@ -0,0 +1,141 @@
+use axum::{extract::State, Json};
+use serde::{Deserialize, Serialize};
+
+use crate::{bad_gateway, bad_request, model, AppState, BackendError, JsonBody};
+
+// Most documents one similarity request may rank; they're all embedded in one call.
+const MAX_DOCUMENTS: usize = 1000;
+
+// Either a single text or a batch; Ollama's /api/embed accepts both.
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum EmbedInput {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Deserialize)]
+pub struct EmbedReq {
+    model: Option<String>,
+    input: EmbedInput,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct EmbedResp {
+    embeddings: Vec<Vec<f32>>,
+}
+
+#[derive(Deserialize)]
+pub struct SimilarityReq {
+    model: Option<String>,
+    query: String,
+    documents: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct SimilarityResp {
+    model: String,
+    // Best match first.
+    results: Vec<Ranked>,
+}
+
+#[derive(Serialize)]
+pub struct Ranked {
+    // Position in the request's `documents`.
+    index: usize,
+    document: String,
+    score: f32,
+}
+
+pub async fn embeddings(State(state): State<AppState>, JsonBody(req): JsonBody<EmbedReq>) -> Result<Json<EmbedResp>, BackendError> {
+    let model = embed_model(&state, req.model.as_deref())?;
+    tracing::info!(%model, "embeddings request");
+    Ok(Json(embed(&state, &model, req.input).await?))
+}
+
+// POST /api/similarity: `documents` ranked by cosine similarity to `query`, so clients don't
+// have to do the vector math themselves.
+pub async fn similarity(
+    State(state): State<AppState>,
+    JsonBody(req): JsonBody<SimilarityReq>,
+) -> Result<Json<SimilarityResp>, BackendError> {
+    if req.documents.is_empty() {
+        return Err(bad_request("documents must not be empty".to_string()));
+    }
+    if req.documents.len() > MAX_DOCUMENTS {
+        return Err(bad_request(format!(
+            "documents may hold at most {} entries, got {}",
+            MAX_DOCUMENTS,
+            req.documents.len()
+        )));
+    }
+    let model = embed_model(&state, req.model.as_deref())?;
+    tracing::info!(%model, documents = req.documents.len(), "similarity request");
+
+    let input = std::iter::once(req.query).chain(req.documents.iter().cloned()).collect();
+    let EmbedResp { embeddings } = embed(&state, &model, EmbedInput::Many(input)).await?;
+    let Some((query, documents)) = embeddings.split_first().filter(|(_, docs)| docs.len() == req.documents.len()) else {
+        let error = format!("Ollama returned {} embeddings for {} inputs", embeddings.len(), req.documents.len() + 1);
+        return Err(bad_gateway(error, None));
+    };
+    let mut results: Vec<Ranked> = req
+        .documents
+        .into_iter()
+        .zip(documents)
+        .enumerate()
+        .map(|(index, (document, embedding))| Ranked { index, document, score: cosine(query, embedding) })
+        .collect();
+    results.sort_by(|a, b| b.score.total_cmp(&a.score));
+    Ok(Json(SimilarityResp { model, results }))
+}
+
+// The model a request asked for, or EMBED_MODEL.
+fn embed_model(state: &AppState, requested: Option<&str>) -> Result<String, BackendError> {
+    let model = match requested {
+        Some(m) => model::normalize_model(Some(m), &state.config.aliases, &state.config.default_model),
+        None => state.config.embed_model.clone(),
+    };
+    model::check_allowed(&model, state.config.allowed_models.as_deref())?;
+    Ok(model)
+}
+
+async fn embed(state: &AppState, model: &str, input: EmbedInput) -> Result<EmbedResp, BackendError> {
+    let body = serde_json::json!({ "model": model, "input": input });
+    let json = state.ollama().post("/api/embed", &body).await?;
+    serde_json::from_value::<EmbedResp>(json).map_err(|e| {
+        tracing::error!(error = %e, "unexpected embed response from ollama");
+        bad_gateway(format!("Unexpected embed response from Ollama: {}", e), None)
+    })
+}
+
+// 0 for vectors of different lengths or with no direction, rather than NaN.
+fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norms = a.iter().map(|x| x * x).sum::<f32>().sqrt() * b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norms == 0.0 {
+        0.0
+    } else {
+        dot / norms
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cosine_compares_direction_only() {
+        assert!((cosine(&[1.0, 2.0], &[2.0, 4.0]) - 1.0).abs() < 1e-6);
+        assert!(cosine(&[1.0, 0.0], &[0.0, 3.0]).abs() < 1e-6);
+        assert!((cosine(&[1.0, 0.0], &[-1.0, 0.0]) + 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn cosine_is_zero_for_vectors_it_cant_compare() {
+        assert_eq!(cosine(&[0.0, 0.0], &[1.0, 1.0]), 0.0);
+        assert_eq!(cosine(&[1.0], &[1.0, 1.0]), 0.0);
+    }
+}
@@ -0,0 +1,68 @@
+use axum::{
+    body::{to_bytes, Body},
+    extract::Request,
+    http::{header, uri::PathAndQuery, Uri},
+    middleware::Next,
+    response::Response,
+};
+use serde_json::{json, Value};
+
+use crate::request_id;
+
+// Every /api route is also served under this prefix, with each successful JSON reply wrapped
+// as `{ data, meta: { model, request_id } }`. Streams and errors are the same on both.
+const PREFIX: &str = "/api/v2";
+
+// Marks a request that came in under PREFIX.
+#[derive(Clone, Copy)]
+struct Enveloped;
+
+// Route /api/v2/... to the /api/... handler. Wraps the whole app, since the path has to change
+// before routing.
+pub async fn strip_prefix(mut req: Request, next: Next) -> Response {
+    let rest = req.uri().path().strip_prefix(PREFIX).filter(|rest| rest.starts_with('/'));
+    if let Some(rest) = rest {
+        let path = match req.uri().query() {
+            Some(query) => format!("/api{}?{}", rest, query),
+            None => format!("/api{}", rest),
+        };
+        let mut parts = req.uri().clone().into_parts();
+        parts.path_and_query = Some(PathAndQuery::try_from(path).expect("a valid path minus its prefix is valid"));
+        *req.uri_mut() = Uri::from_parts(parts).expect("only the path changed");
+        req.extensions_mut().insert(Enveloped);
+    }
+    next.run(req).await
+}
+
+// Wrap successful JSON replies to /api/v2 requests. Has to sit inside the compression layer to
+// see the body as plain JSON.
+pub async fn wrap(req: Request, next: Next) -> Response {
+    let enveloped = req.extensions().get::<Enveloped>().is_some();
+    let id = req.headers().get(request_id::HEADER).and_then(|v| v.to_str().ok()).map(str::to_string);
+    let resp = next.run(req).await;
+    let is_json = resp
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|v| v.as_bytes().starts_with(b"application/json"));
+    if !enveloped || !resp.status().is_success() || !is_json {
+        return resp;
+    }
+
+    let (mut parts, body) = resp.into_parts();
+    let data = match to_bytes(body, usize::MAX).await.map(|bytes| serde_json::from_slice::<Value>(&bytes)) {
+        Ok(Ok(data)) => data,
+        Ok(Err(e)) => {
+            tracing::warn!(error = %e, "reply to wrap in an envelope is not valid json");
+            return Response::from_parts(parts, Body::empty());
+        }
+        Err(e) => {
+            tracing::warn!(error = %e, "failed to read reply to wrap in an envelope");
+            return Response::from_parts(parts, Body::empty());
+        }
+    };
+    // The model that answered, for replies that say.
+    let model = ["used_model", "model"].iter().find_map(|key| data[key].as_str()).map(str::to_string);
+    parts.headers.remove(header::CONTENT_LENGTH);
+    let body = json!({ "data": data, "meta": { "model": model, "request_id": id } });
+    Response::from_parts(parts, Body::from(body.to_string()))
+}
@@ -0,0 +1,110 @@
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+
+// Everything a handler can fail with. Each case maps to one status code, and all of them to
+// the same JSON body shape, so clients can handle errors the same way whatever the route.
+#[derive(Debug)]
+pub enum BackendError {
+    // The request is malformed. `detail` is a parser's own message, when there is one.
+    InvalidRequest { error: String, detail: Option<String> },
+    // The body is bigger than MAX_BODY_BYTES.
+    TooLarge { error: String, detail: Option<String> },
+    // A model that isn't installed, with the ones that are when they could be listed.
+    ModelNotFound { model: String, available_models: Option<Vec<String>>, hint: Option<String> },
+    // Something this server won't do for anyone, e.g. a model outside ALLOWED_MODELS.
+    Forbidden(String),
+    // Anything else the client asked for that doesn't exist: a conversation, a generation, a route.
+    NotFound(String),
+    // Couldn't get an answer out of Ollama at all.
+    OllamaUnreachable(String),
+    // Ollama took longer than OLLAMA_TIMEOUT_SECS.
+    Timeout(String),
+    // Ollama answered, but with an error or something we can't use. `status` is its HTTP
+    // status when the failure came with one.
+    UpstreamStatus { error: String, status: Option<u16> },
+    // The request clashes with an earlier one, e.g. a reused Idempotency-Key.
+    Conflict(String),
+    // Every generation slot and queue place is taken, or the server is shutting down.
+    Busy(String),
+    // The route needs something this server wasn't configured with, e.g. a database.
+    Unsupported(String),
+    Internal(String),
+}
+
+#[derive(Serialize, Default, Debug)]
+pub struct ErrorBody {
+    pub error: String,
+    pub upstream_status: Option<u16>,
+    // Set on "model not found" errors.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub available_models: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hint: Option<String>,
+    // Set when the request body couldn't be parsed: the parser's own message.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl BackendError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            BackendError::InvalidRequest { .. } => StatusCode::BAD_REQUEST,
+            BackendError::TooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            BackendError::Forbidden(_) => StatusCode::FORBIDDEN,
+            BackendError::ModelNotFound { .. } | BackendError::NotFound(_) => StatusCode::NOT_FOUND,
+            BackendError::OllamaUnreachable(_) | BackendError::UpstreamStatus { .. } => StatusCode::BAD_GATEWAY,
+            // A 504 so clients can tell a wedged model from an unreachable one.
+            BackendError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            BackendError::Conflict(_) => StatusCode::CONFLICT,
+            BackendError::Busy(_) => StatusCode::SERVICE_UNAVAILABLE,
+            BackendError::Unsupported(_) => StatusCode::NOT_IMPLEMENTED,
+            BackendError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn is_client_error(&self) -> bool {
+        self.status().is_client_error()
+    }
+
+    // What went wrong, as the body's `error` says it.
+    pub fn message(&self) -> String {
+        match self {
+            BackendError::InvalidRequest { error, .. }
+            | BackendError::TooLarge { error, .. }
+            | BackendError::UpstreamStatus { error, .. } => error.clone(),
+            BackendError::ModelNotFound { model, .. } => format!("model \"{}\" is not installed", model),
+            BackendError::Forbidden(error)
+            | BackendError::NotFound(error)
+            | BackendError::OllamaUnreachable(error)
+            | BackendError::Timeout(error)
+            | BackendError::Conflict(error)
+            | BackendError::Busy(error)
+            | BackendError::Unsupported(error)
+            | BackendError::Internal(error) => error.clone(),
+        }
+    }
+
+    pub fn into_body(self) -> ErrorBody {
+        let error = self.message();
+        match self {
+            BackendError::InvalidRequest { detail, .. } | BackendError::TooLarge { detail, .. } => {
+                ErrorBody { error, detail, ..Default::default() }
+            }
+            BackendError::ModelNotFound { available_models, hint, .. } => {
+                ErrorBody { error, upstream_status: Some(404), available_models, hint, ..Default::default() }
+            }
+            BackendError::UpstreamStatus { status, .. } => ErrorBody { error, upstream_status: status, ..Default::default() },
+            _ => ErrorBody { error, ..Default::default() },
+        }
+    }
+}
+
+impl IntoResponse for BackendError {
+    fn into_response(self) -> Response {
+        (self.status(), Json(self.into_body())).into_response()
+    }
+}
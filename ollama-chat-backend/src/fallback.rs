@@ -0,0 +1,35 @@
+use axum::{
+    extract::Request,
+    http::{header, Method, StatusCode, Uri},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+
+use crate::{request_id, BackendError, ErrorBody};
+
+// Unknown paths get a JSON 404 like every other error, instead of axum's empty one.
+pub async fn not_found(method: Method, uri: Uri) -> BackendError {
+    BackendError::NotFound(format!("no route for {} {}", method, uri.path()))
+}
+
+// axum answers a known path with the wrong method with an empty 405; give it a JSON body that
+// names the methods the route does take. This wraps the whole app, outside the request id
+// layers, so it tags the body with the id itself.
+pub async fn explain_method_not_allowed(req: Request, next: Next) -> Response {
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let resp = next.run(req).await;
+    if resp.status() != StatusCode::METHOD_NOT_ALLOWED || resp.headers().contains_key(header::CONTENT_TYPE) {
+        return resp;
+    }
+    let allow = resp.headers().get(header::ALLOW).and_then(|v| v.to_str().ok()).unwrap_or_default().to_string();
+    let body = ErrorBody {
+        error: format!("{} is not allowed on {}; use {}", method, path, allow),
+        ..Default::default()
+    };
+    let id = resp.headers().get(request_id::HEADER).and_then(|v| v.to_str().ok()).unwrap_or_default().to_string();
+    let (mut parts, _) = resp.into_parts();
+    parts.headers.remove(header::CONTENT_LENGTH);
+    request_id::add_to_error_body((parts, Json(body)).into_response(), &id).await
+}
@@ -0,0 +1,90 @@
+use axum::{
+    extract::State,
+    response::{sse::Event, IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use std::time::Instant;
+use tokio::sync::mpsc;
+
+use crate::stream::{self, SseItem};
+use crate::ollama::{OllamaGenerateRequest, OllamaOptions};
+use crate::{bodies, capped_num_predict, model, AppState, JsonBody};
+
+#[derive(Deserialize)]
+pub struct GenerateReq {
+    prompt: String,
+    model: Option<String>,
+    stream: Option<bool>,
+    // Skip the model's prompt template; the prompt must then be fully formatted already.
+    raw: Option<bool>,
+    // Text after the gap, for fill-in-the-middle: the model writes what goes between `prompt`
+    // and this.
+    suffix: Option<String>,
+}
+
+#[derive(Serialize)]
+struct GenerateResp {
+    response: String,
+}
+
+// Single-prompt completion via Ollama's /api/generate. Streams over SSE when `stream` is true,
+// with /api/chat/stream's `model`, `generation`, token, `complete`, `done` and `stats` events.
+pub async fn generate(State(state): State<AppState>, JsonBody(req): JsonBody<GenerateReq>) -> Response {
+    let started = Instant::now();
+    let model = model::normalize_model(req.model.as_deref(), &state.config.aliases, &state.config.default_model);
+    if let Err(e) = model::check_allowed(&model, state.config.allowed_models.as_deref()) {
+        return e.into_response();
+    }
+    let stream = req.stream.unwrap_or(false);
+    tracing::info!(%model, stream, "generate request");
+    bodies::log_prompt(state.config.log_bodies, &req.prompt);
+
+    // The model's preset and the server's defaults apply here too; MAX_OUTPUT_TOKENS still
+    // caps them.
+    let options = state.config.presets.get(&model).or(state.config.default_options.clone());
+    let num_predict = capped_num_predict(options.num_predict, state.config.max_output_tokens);
+    let body = OllamaGenerateRequest {
+        model,
+        prompt: req.prompt,
+        raw: req.raw,
+        suffix: req.suffix,
+        stream,
+        options: OllamaOptions { num_predict, ..options },
+    };
+
+    let permit = match state.generations.acquire().await {
+        Ok(p) => p,
+        Err(e) => return e.into_response(),
+    };
+
+    if stream {
+        let resp = match state.ollama().generate_stream(&body).await {
+            Ok(r) => r,
+            Err(e) if e.is_client_error() => return e.into_response(),
+            Err(e) => return stream::single_event(stream::error_event(&e.message())).into_response(),
+        };
+        let (tx, rx) = mpsc::channel::<SseItem>(stream::CHANNEL_CAPACITY);
+        // Registered so /api/cancel can stop it, and graceful shutdown end it with a `shutdown`
+        // event.
+        let registration = state.cancellations.register();
+        let announce = serde_json::json!({ "model": body.model }).to_string();
+        let _ = tx.try_send(Ok(Event::default().event("model").data(announce)));
+        let generation = serde_json::json!({ "id": registration.id }).to_string();
+        let _ = tx.try_send(Ok(Event::default().event("generation").data(generation)));
+        let encode = stream::with_complete(stream::native_encoder(state.config.sse_text_deltas, false));
+        let encode = stream::with_stats(started, encode);
+        let encode = bodies::log_stream(state.config.log_bodies, encode);
+        let encode = stream::with_output_cap(state.config.max_output_tokens, encode);
+        stream::spawn_forwarder(resp, tx, permit, Some(registration), state.config.stream_idle_timeout, encode);
+        return stream::sse(rx, state.config.sse_keep_alive, state.config.sse_retry).into_response();
+    }
+
+    match state.ollama().generate(&body).await {
+        Ok(resp) => {
+            bodies::log_reply(state.config.log_bodies, &resp.response);
+            Json(GenerateResp { response: resp.response }).into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
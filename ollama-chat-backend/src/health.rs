@@ -0,0 +1,80 @@
+use std::time::Duration;
+
+use axum::{extract::State, http::StatusCode, Json};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::AppState;
+
+const READY_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Liveness: answers as long as the process is serving, regardless of Ollama.
+pub async fn live() -> Json<Value> {
+    Json(json!({ "status": "ok" }))
+}
+
+#[derive(Serialize)]
+pub struct Versions {
+    backend: &'static str,
+    // `None` when Ollama can't be reached.
+    ollama: Option<String>,
+}
+
+// GET /api/version: this backend's version and the connected Ollama's, for bug reports.
+pub async fn version(State(state): State<AppState>) -> Json<Versions> {
+    let result = state
+        .client
+        .get(state.upstreams.pick().url("/api/version"))
+        .timeout(READY_TIMEOUT)
+        .send()
+        .await;
+    let ollama = match result {
+        Ok(resp) if resp.status().is_success() => match resp.json::<Value>().await {
+            Ok(v) => v["version"].as_str().map(str::to_string),
+            Err(e) => {
+                tracing::warn!(error = %e, "invalid /api/version response from ollama");
+                None
+            }
+        },
+        Ok(resp) => {
+            tracing::warn!(status = %resp.status(), "ollama returned an error for /api/version");
+            None
+        }
+        Err(e) => {
+            tracing::warn!(error = %e, "failed to reach ollama for /api/version");
+            None
+        }
+    };
+    Json(Versions { backend: env!("CARGO_PKG_VERSION"), ollama })
+}
+
+// Readiness: only ok once an Ollama instance answers /api/version. Every instance is asked,
+// and the answers update which ones requests skip.
+pub async fn ready(State(state): State<AppState>) -> (StatusCode, Json<Value>) {
+    let probes = state.upstreams.all().map(|upstream| {
+        let client = &state.client;
+        async move {
+            let result = client.get(upstream.url("/api/version")).timeout(READY_TIMEOUT).send().await;
+            let outcome = match result {
+                Ok(resp) if resp.status().is_success() => Ok(()),
+                Ok(resp) => Err(format!("ollama returned {}", resp.status())),
+                Err(e) => Err(e.to_string()),
+            };
+            match &outcome {
+                Ok(()) => upstream.mark_up(),
+                Err(error) => {
+                    tracing::warn!(ollama = upstream.base(), %error, "ollama instance is not ready");
+                    upstream.mark_down();
+                }
+            }
+            outcome
+        }
+    });
+    let outcomes = futures_util::future::join_all(probes).await;
+
+    if outcomes.iter().any(Result::is_ok) {
+        return (StatusCode::OK, Json(json!({ "status": "ok" })));
+    }
+    let error = outcomes.into_iter().find_map(Result::err);
+    (StatusCode::SERVICE_UNAVAILABLE, Json(json!({ "status": "unavailable", "error": error })))
+}
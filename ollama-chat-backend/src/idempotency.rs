@@ -0,0 +1,87 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::http::HeaderMap;
+use serde::Serialize;
+
+use crate::reply_cache::{self, Key, ReplyCache};
+use crate::{bad_request, BackendError};
+
+pub const HEADER: &str = "idempotency-key";
+// Sent on a reply that was replayed for a repeated key rather than generated again.
+pub const REPLAYED_HEADER: &str = "idempotent-replayed";
+// Keys remembered at once; the oldest go first.
+pub const MAX_KEYS: usize = 1000;
+const MAX_KEY_LEN: usize = 255;
+
+// Replies by Idempotency-Key, each with the body of the request that produced it, and the
+// keys whose first request is still generating.
+pub struct IdempotentReplies<T> {
+    replies: ReplyCache<(Key, T)>,
+    in_flight: Mutex<HashSet<Key>>,
+}
+
+// What a request with a key should do: send back the reply already given, or generate one
+// and `finish` with it.
+pub enum Claim<T> {
+    Replay(T),
+    Run(InFlight<T>),
+}
+
+// A key whose request is generating. Dropping it without `finish`, e.g. on a failure or when
+// the client goes away, frees the key for a retry.
+pub struct InFlight<T> {
+    replies: Arc<IdempotentReplies<T>>,
+    key: Key,
+    fingerprint: Key,
+}
+
+// The request's Idempotency-Key and a fingerprint of its body (the body serialized whole), or
+// `None` when it sent no key.
+pub fn key(headers: &HeaderMap, req: &impl Serialize) -> Result<Option<(Key, Key)>, BackendError> {
+    let Some(value) = headers.get(HEADER) else {
+        return Ok(None);
+    };
+    let key = value.to_str().ok().map(str::trim).filter(|k| !k.is_empty() && k.len() <= MAX_KEY_LEN);
+    let Some(key) = key else {
+        return Err(bad_request(format!("{} must be 1 to {} visible characters", HEADER, MAX_KEY_LEN)));
+    };
+    Ok(Some((key.as_bytes().to_vec(), reply_cache::key(req))))
+}
+
+impl<T: Clone> IdempotentReplies<T> {
+    pub fn new(ttl: Duration) -> IdempotentReplies<T> {
+        IdempotentReplies { replies: ReplyCache::new(MAX_KEYS, ttl), in_flight: Mutex::default() }
+    }
+
+    // The reply already given for this key, or the right to generate it. A key reused for a
+    // different request is a client bug, so it gets a 409 rather than someone else's reply, and
+    // so does a repeat while the first request is still running, rather than a second
+    // generation.
+    pub fn claim(self: &Arc<Self>, key: Key, fingerprint: Key) -> Result<Claim<T>, BackendError> {
+        // Held across both checks, so two requests can't both find the key free.
+        let mut in_flight = self.in_flight.lock().unwrap();
+        match self.replies.get(&key) {
+            Some((seen, reply)) if seen == fingerprint => return Ok(Claim::Replay(reply)),
+            Some(_) => return Err(BackendError::Conflict(format!("{} was already used for a different request", HEADER))),
+            None => {}
+        }
+        if !in_flight.insert(key.clone()) {
+            return Err(BackendError::Conflict(format!("a request with this {} is still running", HEADER)));
+        }
+        Ok(Claim::Run(InFlight { replies: self.clone(), key, fingerprint }))
+    }
+}
+
+impl<T: Clone> InFlight<T> {
+    pub fn finish(self, reply: T) {
+        self.replies.replies.insert(self.key.clone(), (self.fingerprint.clone(), reply));
+    }
+}
+
+impl<T> Drop for InFlight<T> {
+    fn drop(&mut self) {
+        self.replies.in_flight.lock().unwrap().remove(&self.key);
+    }
+}
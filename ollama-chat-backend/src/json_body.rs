@@ -0,0 +1,45 @@
+use axum::{
+    async_trait,
+    extract::{rejection::JsonRejection, FromRequest, Request},
+    http::StatusCode,
+    Json,
+};
+use serde::de::DeserializeOwned;
+
+use crate::BackendError;
+
+// `Json` for request bodies, but a payload that can't be read comes back as our usual JSON
+// error, with what went wrong in `detail`, instead of axum's terse plain-text rejection.
+pub(crate) struct JsonBody<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequest<S> for JsonBody<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = BackendError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        match Json::<T>::from_request(req, state).await {
+            Ok(Json(value)) => Ok(JsonBody(value)),
+            Err(rejection) => Err(explain(rejection)),
+        }
+    }
+}
+
+fn explain(rejection: JsonRejection) -> BackendError {
+    let detail = rejection.body_text();
+    tracing::debug!(%detail, "rejected request body");
+    let invalid = |error: &str| BackendError::InvalidRequest { error: error.to_string(), detail: Some(detail.clone()) };
+    match &rejection {
+        JsonRejection::MissingJsonContentType(_) => invalid("expected a JSON body with Content-Type: application/json"),
+        JsonRejection::JsonSyntaxError(_) => invalid("request body is not valid JSON"),
+        JsonRejection::JsonDataError(_) => invalid("request body doesn't have the expected fields; see detail"),
+        // Oversized bodies keep their 413, which `body_limit::explain_too_large` rewrites.
+        JsonRejection::BytesRejection(_) if rejection.status() == StatusCode::PAYLOAD_TOO_LARGE => {
+            BackendError::TooLarge { error: "request body is too large".to_string(), detail: Some(detail) }
+        }
+        _ => invalid("failed to read the request body"),
+    }
+}
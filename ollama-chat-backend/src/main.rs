@@ -1,226 +1,1036 @@
-use axum::{routing::post, Json, Router};
+use axum::{
+    extract::{Query, State},
+    routing::{get, post},
+    Json, Router,
+};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use futures_util::stream::TryStreamExt;
-use axum::response::IntoResponse;
-use axum::response::sse::{Sse, Event};
-use tokio::sync::mpsc;
-use tokio_stream::wrappers::ReceiverStream;
+use base64::Engine;
+use axum::response::sse::{Event, Sse};
+use axum::http::{HeaderMap, HeaderValue};
+use axum::response::{IntoResponse, Response};
+use std::future::{Future, IntoFuture};
+use tokio::sync::{mpsc, watch};
 use std::net::SocketAddr;
-use tower_http::cors::{Any, CorsLayer};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tower_http::{
+    compression::CompressionLayer,
+    trace::{DefaultOnResponse, TraceLayer},
+    LatencyUnit,
+};
+use tracing::{Instrument, Level};
+
+mod auth;
+mod batch;
+mod bodies;
+mod body_limit;
+mod cancel;
+mod concurrency;
+mod conversations;
+mod config;
+mod context;
+mod cors;
+mod embeddings;
+mod envelope;
+mod error;
+mod fallback;
+mod generate;
+mod health;
+mod idempotency;
+mod json_body;
+mod mock;
+mod model;
+mod models;
+mod ollama;
+mod openai;
+mod presets;
+mod pull;
+mod ratelimit;
+mod reply_cache;
+mod request_id;
+mod resume;
+mod store;
+mod stream;
+mod system_prompt;
+mod telemetry;
+mod title;
+mod tls;
+mod upstreams;
+mod ws;
+
+use cancel::Cancellations;
+use concurrency::{Admission, GenerationGate};
+use json_body::JsonBody;
+use config::Config;
+use error::{BackendError, ErrorBody};
+use idempotency::{Claim, IdempotentReplies};
+use metrics_exporter_prometheus::PrometheusHandle;
+use models::TagCache;
+use ollama::{OllamaChatRequest, OllamaOptions};
+use ratelimit::RateLimiter;
+use reply_cache::ReplyCache;
+use resume::Resumable;
+use store::Store;
+use stream::SseItem;
+use upstreams::Upstreams;
+
+// Shared state handed to every handler.
+#[derive(Clone)]
+struct AppState {
+    config: Arc<Config>,
+    // One pooled client for all upstream calls; cloning it is cheap.
+    client: reqwest::Client,
+    // `None` when RATE_LIMIT_PER_MINUTE is 0.
+    rate_limiter: Option<Arc<RateLimiter>>,
+    generations: Arc<GenerationGate>,
+    tags: Arc<TagCache>,
+    metrics: PrometheusHandle,
+    cancellations: Arc<Cancellations>,
+    // `None` when DATABASE_URL is unset.
+    store: Option<Arc<Store>>,
+    // `None` when RESPONSE_CACHE_SIZE is 0.
+    replies: Option<Arc<ReplyCache<ChatResp>>>,
+    // `None` when IDEMPOTENCY_TTL_SECS is 0.
+    idempotent: Option<Arc<IdempotentReplies<ChatResp>>>,
+    // `None` when STREAM_RESUME_SECS is 0.
+    resumable: Option<Arc<Resumable>>,
+    upstreams: Arc<Upstreams>,
+}
 
 #[derive(Serialize, Deserialize, Clone)]
 struct Msg {
     role: String,
     content: String,
+    // Base64-encoded images for vision models such as llava.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    images: Option<Vec<String>>,
+    // Calls an assistant message made, sent back with the `tool` results in a later turn.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tool_calls: Option<Value>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Default, Serialize, Deserialize)]
 struct ChatReq {
     messages: Vec<Msg>,
     model: Option<String>,
+    // True on /api/chat for an SSE stream, as from /api/chat/stream.
     stream: Option<bool>,
+    // Persona prepended as a system message.
+    system: Option<String>,
+    // Sampling controls; anything left out keeps Ollama's default.
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    seed: Option<i64>,
+    num_predict: Option<i32>,
+    // Context window in tokens; falls back to OLLAMA_NUM_CTX, then Ollama's default.
+    num_ctx: Option<u32>,
+    // Strings that end generation when the model produces them.
+    stop: Option<Vec<String>>,
+    // Hardware placement: model layers to offload to the GPU(s), and CPU threads to use.
+    num_gpu: Option<i32>,
+    num_thread: Option<i32>,
+    // Output constraint passed through to Ollama: "json" or a JSON schema.
+    format: Option<Value>,
+    // Function definitions the model may call, passed through to Ollama as given.
+    tools: Option<Value>,
+    // Ask a thinking model for its reasoning, which comes back apart from the answer.
+    think: Option<bool>,
+    // How long Ollama keeps the model loaded afterwards, e.g. "5m", or "-1" to keep it resident.
+    keep_alive: Option<String>,
+    // Save the latest user message and the reply to this stored conversation.
+    conversation_id: Option<String>,
+    // Estimated token budget for the conversation; the oldest turns are dropped to fit.
+    // Falls back to MAX_CONTEXT_TOKENS.
+    max_context_tokens: Option<u32>,
+    // Streams only: send events at least this far apart, for a steady pace.
+    min_token_interval_ms: Option<u64>,
+    // Only read so it can be refused: fill-in-the-middle is a /api/generate feature.
+    #[serde(skip_serializing)]
+    suffix: Option<Value>,
+    // Include Ollama's whole reply as `raw`; set from `?debug=true` or `X-Debug: true`.
+    #[serde(skip)]
+    debug: bool,
 }
 
-// Keep structs for the expected shape, but we'll parse more flexibly below.
-#[derive(Deserialize)]
-struct OllamaChatMessage {
-    content: String,
+const VALID_ROLES: &[&str] = &["system", "user", "assistant", "tool"];
+const MAX_STOP_SEQUENCES: usize = 16;
+// Generous upper bounds; no model has more layers, and no box more useful threads.
+const MAX_NUM_GPU: i32 = 1024;
+const MAX_NUM_THREAD: i32 = 1024;
+// Warn once a conversation is estimated to fill this share of the context window.
+const CONTEXT_WARN_RATIO: f64 = 0.9;
+// A rough average, for estimating token counts without the model's tokenizer.
+const CHARS_PER_TOKEN: usize = 4;
+// Slowest pacing a stream may ask for; any slower and a reply would hold its slot for ages.
+const MAX_TOKEN_INTERVAL_MS: u64 = 1000;
+// How long before the drain window closes open streams are sent their `shutdown` event.
+const SHUTDOWN_NOTICE: Duration = Duration::from_secs(1);
+
+impl ChatReq {
+    // Catch malformed conversations here rather than letting Ollama reject them cryptically.
+    // Roles named in ROLE_MAP are renamed first, so only roles it doesn't know are rejected.
+    fn validate(&mut self, config: &Config) -> Result<(), BackendError> {
+        for m in &mut self.messages {
+            if let Some(role) = config.role_map.get(&m.role) {
+                m.role = role.clone();
+            }
+        }
+        if self.messages.is_empty() {
+            return Err(bad_request("messages must not be empty".to_string()));
+        }
+        if let Some(max) = config.max_messages.filter(|&max| self.messages.len() > max) {
+            return Err(bad_request(format!(
+                "messages has {} entries, more than the {} allowed; send a shorter history",
+                self.messages.len(),
+                max
+            )));
+        }
+        for (i, m) in self.messages.iter().enumerate() {
+            // One pasted document shouldn't get to crowd out the whole context window.
+            if let Some(max) = config.max_message_chars {
+                let chars = m.content.chars().count();
+                if chars > max {
+                    return Err(bad_request(format!(
+                        "messages[{}].content is {} characters, more than the {} allowed",
+                        i, chars, max
+                    )));
+                }
+            }
+            if !VALID_ROLES.contains(&m.role.as_str()) {
+                return Err(bad_request(format!(
+                    "messages[{}].role must be one of {}, got {:?}",
+                    i,
+                    VALID_ROLES.join(", "),
+                    m.role
+                )));
+            }
+            for (j, image) in m.images.iter().flatten().enumerate() {
+                if base64::engine::general_purpose::STANDARD.decode(image).is_err() {
+                    return Err(bad_request(format!("messages[{}].images[{}] is not valid base64", i, j)));
+                }
+            }
+        }
+        for (name, value, max) in [("num_gpu", self.num_gpu, MAX_NUM_GPU), ("num_thread", self.num_thread, MAX_NUM_THREAD)] {
+            if let Some(v) = value.filter(|v| !(0..=max).contains(v)) {
+                return Err(bad_request(format!("{} must be between 0 and {}, got {}", name, max, v)));
+            }
+        }
+        if let Some(ms) = self.min_token_interval_ms.filter(|&ms| ms > MAX_TOKEN_INTERVAL_MS) {
+            return Err(bad_request(format!(
+                "min_token_interval_ms must be at most {}, got {}",
+                MAX_TOKEN_INTERVAL_MS, ms
+            )));
+        }
+        if self.suffix.is_some() {
+            return Err(bad_request("suffix is only supported by /api/generate".to_string()));
+        }
+        if self.tools.as_ref().is_some_and(|t| !t.is_array()) {
+            return Err(bad_request("tools must be an array of tool definitions".to_string()));
+        }
+        if let Some(stop) = &self.stop {
+            if stop.len() > MAX_STOP_SEQUENCES {
+                return Err(bad_request(format!(
+                    "stop allows at most {} sequences, got {}",
+                    MAX_STOP_SEQUENCES,
+                    stop.len()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    // Whether the reply was asked to be JSON, so has to parse as such.
+    fn wants_json(&self) -> bool {
+        self.format.as_ref().and_then(Value::as_str) == Some("json")
+    }
+
+    // How far apart to send stream events; `None` sends them as they come.
+    fn token_interval(&self) -> Option<Duration> {
+        self.min_token_interval_ms.filter(|&ms| ms > 0).map(Duration::from_millis)
+    }
+
+    // Drop the oldest turns to fit the context budget, if there is one. Returns how many
+    // messages were dropped.
+    fn trim_context(&mut self, config: &Config) -> usize {
+        let Some(budget) = self.max_context_tokens.or(config.max_context_tokens) else {
+            return 0;
+        };
+        // The template's length stands in for the rendered prompt's, which is about the same.
+        let reserved = self.system.as_ref().map_or(0, |s| s.chars().count())
+            + config.system_prompt_template.as_ref().map_or(0, |t| t.chars().count());
+        let dropped = context::trim(&mut self.messages, budget as usize, reserved);
+        if dropped > 0 {
+            tracing::info!(dropped, budget, "dropped the oldest messages to fit the context budget");
+        }
+        dropped
+    }
+
+    // Ollama's `options` for `model`: the request's own, then the model's preset for anything it
+    // left unset, then the server-wide defaults.
+    fn options(&self, model: &str, config: &Config) -> OllamaOptions {
+        let own = OllamaOptions {
+            temperature: self.temperature,
+            top_p: self.top_p,
+            seed: self.seed,
+            num_predict: self.num_predict.map(i64::from),
+            num_ctx: self.num_ctx,
+            stop: self.stop.clone(),
+            num_gpu: self.num_gpu,
+            num_thread: self.num_thread,
+        };
+        let options = own.or(config.presets.get(model)).or(config.default_options.clone());
+        OllamaOptions { num_predict: capped_num_predict(options.num_predict, config.max_output_tokens), ..options }
+    }
+
+    // The conversation as Ollama should see it. A `system` field replaces a leading system
+    // message from the client rather than stacking a second one in front of it, and
+    // SYSTEM_PROMPT_TEMPLATE goes in front of whichever system prompt that leaves.
+    fn messages(&self, model: &str, config: &Config) -> Vec<Msg> {
+        let mut messages = match &self.system {
+            Some(system) => {
+                let rest = match self.messages.first() {
+                    Some(m) if m.role == "system" => &self.messages[1..],
+                    _ => &self.messages[..],
+                };
+                let mut messages = Vec::with_capacity(rest.len() + 1);
+                messages.push(Msg { role: "system".to_string(), content: system.clone(), images: None, tool_calls: None });
+                messages.extend_from_slice(rest);
+                messages
+            }
+            None => self.messages.clone(),
+        };
+        if let Some(template) = &config.system_prompt_template {
+            let prompt = system_prompt::render(template, model, SystemTime::now());
+            match messages.first_mut() {
+                Some(m) if m.role == "system" => m.content = format!("{}\n\n{}", prompt, m.content),
+                _ => {
+                    let system = Msg { role: "system".to_string(), content: prompt, images: None, tool_calls: None };
+                    messages.insert(0, system)
+                }
+            }
+        }
+        messages
+    }
+
+    // The request to send to Ollama's /api/chat.
+    fn ollama_request(&self, model: &str, stream: bool, config: &Config) -> OllamaChatRequest {
+        let messages = self.messages(model, config);
+        let options = self.options(model, config);
+        if let Some(num_ctx) = options.num_ctx {
+            let estimate = estimated_tokens(&messages);
+            if estimate as f64 >= num_ctx as f64 * CONTEXT_WARN_RATIO {
+                tracing::warn!(
+                    estimate,
+                    num_ctx,
+                    "conversation is near the context window; Ollama will drop the oldest messages"
+                );
+            }
+        }
+        OllamaChatRequest {
+            model: model.to_string(),
+            messages,
+            stream,
+            options,
+            format: self.format.clone(),
+            tools: self.tools.clone(),
+            think: self.think,
+            keep_alive: self.keep_alive.as_ref().or(config.keep_alive.as_ref()).map(|k| keep_alive_value(k)),
+        }
+    }
 }
 
-#[derive(Deserialize)]
-struct OllamaChatResp {
-    message: OllamaChatMessage,
+// Rough token count for a conversation. Good enough to tell when a chat is close to the
+// context limit.
+fn estimated_tokens(messages: &[Msg]) -> usize {
+    let chars: usize = messages.iter().map(|m| m.content.chars().count()).sum();
+    chars / CHARS_PER_TOKEN
 }
 
-#[derive(Serialize)]
-struct ChatResp {
-    content: String,
+// The num_predict to send: the client's, but never more than MAX_OUTPUT_TOKENS. Ollama treats
+// negative values as "no limit", so those get the cap too.
+fn capped_num_predict(requested: Option<i64>, cap: Option<u32>) -> Option<i64> {
+    match (requested, cap) {
+        (Some(n), Some(cap)) if n >= 0 => Some(n.min(i64::from(cap))),
+        (_, Some(cap)) => Some(i64::from(cap)),
+        (n, None) => n,
+    }
 }
 
-#[tokio::main]
-async fn main() {
-    let cors = CorsLayer::new().allow_origin(Any).allow_methods(Any).allow_headers(Any);
+// Backstop for non-streaming replies in case a model overshoots num_predict: cut the text at
+// roughly MAX_OUTPUT_TOKENS. Returns whether anything was cut.
+fn truncate_output(content: &mut String, eval_count: Option<u64>, cap: Option<u32>) -> bool {
+    let (Some(count), Some(cap)) = (eval_count, cap) else {
+        return false;
+    };
+    if count <= u64::from(cap) {
+        return false;
+    }
+    let max_chars = cap as usize * CHARS_PER_TOKEN;
+    if let Some((idx, _)) = content.char_indices().nth(max_chars) {
+        content.truncate(idx);
+    }
+    true
+}
 
-    let app = Router::new()
-        .route("/api/chat", post(chat))
-        .route("/api/chat/stream", post(chat_stream))
-        .layer(cors);
+// Ollama reads a bare number as seconds and a string as a Go duration ("5m"), but rejects a
+// unitless string like "-1", so send integers as numbers.
+fn keep_alive_value(raw: &str) -> Value {
+    match raw.trim().parse::<i64>() {
+        Ok(secs) => secs.into(),
+        Err(_) => raw.trim().into(),
+    }
+}
 
-    let addr: SocketAddr = "127.0.0.1:8080".parse().unwrap();
-    println!("Server running on {}", addr);
+#[derive(Serialize, Clone)]
+struct ChatResp {
+    content: String,
+    // The model asked for, after alias resolution. Ollama was sent this first, but after a
+    // fallback another one answered: see `used_model`.
+    model: String,
+    prompt_tokens: Option<u64>,
+    completion_tokens: Option<u64>,
+    total_duration_ms: Option<u64>,
+    // Why generation stopped; "length" means the reply was cut off.
+    done_reason: Option<String>,
+    // The model that answered; differs from the requested one after a fallback.
+    used_model: String,
+    // Oldest messages left out to fit the context budget.
+    dropped_messages: usize,
+    // Tools the model asked to call, in Ollama's `[{ "function": { "name", "arguments" } }]` shape.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_calls: Option<Value>,
+    // The model's reasoning, when it gave any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thinking: Option<String>,
+    // Ollama's reply in full, when the request asked to debug.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    raw: Option<Value>,
+    // Sent as headers rather than in the body; see `timing_headers`.
+    #[serde(skip)]
+    timings: Timings,
+}
 
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+// Where Ollama spent its time on a reply, in milliseconds, each when Ollama reported it.
+#[derive(Clone, Default)]
+struct Timings {
+    load_ms: Option<u64>,
+    prompt_eval_ms: Option<u64>,
+    eval_ms: Option<u64>,
 }
 
-async fn chat(Json(req): Json<ChatReq>) -> Json<ChatResp> {
-    // Normalize model identifiers coming from the frontend.
-    fn normalize_model(opt: Option<String>) -> String {
-        match opt {
-            Some(m) => {
-                // common alias from frontend: "llama3.1" -> "llama3:8b"
-                if m == "llama3.1" {
-                    return "llama3:8b".to_string();
-                }
-                // If frontend sent 'llama3.8b' or similar with a dot, replace with ':'
-                if m.contains('.') && !m.contains(':') {
-                    return m.replace('.', ":");
-                }
-                m
-            }
-            None => "llama3:8b".to_string(),
+// Total, load, prompt eval and eval time, in that order.
+const TIMING_HEADERS: [&str; 4] = ["x-ollama-total-ms", "x-ollama-load-ms", "x-ollama-prompt-eval-ms", "x-ollama-eval-ms"];
+
+// X-Ollama-*-Ms headers for a non-streaming reply, so a client can read the latency breakdown
+// without parsing the body.
+fn timing_headers(reply: &ChatResp) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    let timings = [reply.total_duration_ms, reply.timings.load_ms, reply.timings.prompt_eval_ms, reply.timings.eval_ms];
+    for (name, ms) in TIMING_HEADERS.into_iter().zip(timings) {
+        if let Some(ms) = ms {
+            headers.insert(name, HeaderValue::from(ms));
         }
     }
+    headers
+}
+
+fn bad_request(error: String) -> BackendError {
+    BackendError::InvalidRequest { error, detail: None }
+}
 
-    let model = normalize_model(req.model.clone());
-    println!("using model: {}", model);
+fn bad_gateway(error: String, status: Option<u16>) -> BackendError {
+    BackendError::UpstreamStatus { error, status }
+}
 
-    let body = serde_json::json!({
-        "model": model,
-        "messages": req.messages,
-        "stream": false
-    });
+// A failed call to Ollama, told apart by whether it timed out, never answered, or answered
+// with an error status.
+fn upstream_failure(context: &str, e: reqwest::Error, timeout: Duration) -> BackendError {
+    if e.is_timeout() {
+        return BackendError::Timeout(format!("Ollama did not respond within {}s", timeout.as_secs()));
+    }
+    let error = format!("{}: {}", context, e);
+    match e.status() {
+        Some(status) => bad_gateway(error, Some(status.as_u16())),
+        None => BackendError::OllamaUnreachable(error),
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| "info".into()),
+        )
+        .init();
 
-    let client = reqwest::Client::new();
-    let resp = match client.post("http://127.0.0.1:11434/api/chat").json(&body).send().await {
-        Ok(r) => r,
+    let config = match Config::from_env() {
+        Ok(c) => c,
         Err(e) => {
-            eprintln!("failed to send request to ollama: {}", e);
-            return Json(ChatResp { content: format!("Error contacting Ollama API: {}", e) });
+            tracing::error!("invalid configuration: {}", e);
+            std::process::exit(1);
         }
     };
+    for host in &config.ollama_hosts {
+        tracing::info!("using ollama at {}{}", host, config.ollama_path_prefix);
+    }
+    tracing::info!("loaded {} model aliases", config.aliases.len());
+    if config.presets.len() > 0 {
+        tracing::info!("loaded option presets for {} models", config.presets.len());
+    }
+    if config.default_options != OllamaOptions::default() {
+        let options = serde_json::to_string(&config.default_options).unwrap_or_default();
+        tracing::info!("default ollama options: {}", options);
+    }
+    tracing::info!(
+        "default model: {}",
+        model::normalize_model(None, &config.aliases, &config.default_model)
+    );
+    if let Some(allowed) = &config.allowed_models {
+        tracing::info!("allowed models: {}", allowed.join(", "));
+        let default_model = model::normalize_model(None, &config.aliases, &config.default_model);
+        for m in [&default_model, &config.embed_model] {
+            if model::check_allowed(m, Some(allowed)).is_err() {
+                tracing::warn!("{} is a default model but not in ALLOWED_MODELS, so requests for it are refused", m);
+            }
+        }
+    }
+    match &config.allowed_origins {
+        Some(origins) => tracing::info!("allowed origins: {}", origins.join(", ")),
+        None => tracing::warn!("DEV_MODE: allowing requests from any origin"),
+    }
+    if config.mock_mode {
+        tracing::warn!("MOCK_MODE: chat replies are canned, Ollama is not contacted");
+    }
+    if config.log_bodies {
+        tracing::warn!("LOG_BODIES: chat content is logged in full at debug level");
+    }
+    if config.api_token.is_none() {
+        tracing::warn!("API_TOKEN is not set, the API is open to anyone who can reach it");
+    }
 
-    // Read the whole response body as text first so we can log/fallback if parsing fails.
-    let body_text = match resp.text().await {
-        Ok(t) => t,
+    let client = match reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(5))
+        .pool_idle_timeout(Duration::from_secs(90))
+        .pool_max_idle_per_host(64)
+        .tcp_keepalive(Duration::from_secs(60))
+        .build()
+    {
+        Ok(c) => c,
         Err(e) => {
-            eprintln!("failed to read response body: {}", e);
-            return Json(ChatResp { content: format!("Failed to read response body: {}", e) });
+            tracing::error!("failed to build http client: {}", e);
+            std::process::exit(1);
         }
     };
 
-    // Try to parse JSON; if invalid, return raw body so frontend can surface it.
-    let json: Value = match serde_json::from_str(&body_text) {
-        Ok(v) => v,
+    let addr = config.bind_addr;
+    let metrics = match telemetry::install() {
+        Ok(h) => h,
         Err(e) => {
-            eprintln!("invalid json from ollama: {}\nbody: {}", e, body_text);
-            return Json(ChatResp { content: body_text });
+            tracing::error!("failed to install metrics recorder: {}", e);
+            std::process::exit(1);
         }
     };
 
-    // Walk the JSON to find a `content` string (common locations: message.content or content)
-    fn extract_content(v: &Value) -> Option<String> {
-        match v {
-            Value::String(_) => None,
-            Value::Object(map) => {
-                if let Some(Value::String(s)) = map.get("content") {
-                    return Some(s.clone());
-                }
-                if let Some(msg) = map.get("message") {
-                    if let Some(Value::String(s)) = msg.get("content") {
-                        return Some(s.clone());
-                    }
-                }
-                for (_k, val) in map.iter() {
-                    if let Some(found) = extract_content(val) {
-                        return Some(found);
-                    }
-                }
-                None
+    let store = match &config.database_url {
+        Some(url) => match Store::open(url) {
+            Ok(s) => {
+                tracing::info!("storing conversations in {}", url);
+                Some(Arc::new(s))
             }
-            Value::Array(arr) => {
-                for val in arr.iter() {
-                    if let Some(found) = extract_content(val) {
-                        return Some(found);
-                    }
-                }
-                None
+            Err(e) => {
+                tracing::error!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let tls = match &config.tls {
+        Some(paths) => match tls::load(paths).await {
+            Ok(c) => {
+                tracing::info!("serving https with certificate {}", paths.cert.display());
+                Some(c)
+            }
+            Err(e) => {
+                tracing::error!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let shutdown_grace = config.shutdown_grace;
+    let rate_limiter = match config.rate_limit_per_minute {
+        0 => None,
+        n => Some(Arc::new(RateLimiter::new(n))),
+    };
+    let generations = Arc::new(GenerationGate::new(
+        config.max_concurrent_generations,
+        config.generation_queue_size,
+        config.generation_queue_timeout,
+    ));
+    let replies = match config.response_cache_size {
+        0 => None,
+        n => Some(Arc::new(ReplyCache::new(n, config.response_cache_ttl))),
+    };
+    let idempotent = config.idempotency_ttl.map(|ttl| Arc::new(IdempotentReplies::new(ttl)));
+    let resumable = config.stream_resume.map(|window| Arc::new(Resumable::new(window)));
+    let upstreams = Arc::new(Upstreams::new(&config.ollama_hosts, &config.ollama_path_prefix));
+    let state = AppState {
+        config: Arc::new(config),
+        client,
+        rate_limiter,
+        generations,
+        tags: Arc::new(TagCache::default()),
+        metrics,
+        cancellations: Arc::new(Cancellations::default()),
+        store,
+        replies,
+        idempotent,
+        resumable,
+        upstreams,
+    };
+
+    let cancellations = state.cancellations.clone();
+    let cors = cors::layer(&state.config);
+
+    // Routes that run a generation on the GPU; these are rate limited per client.
+    let generation = Router::new()
+        .route("/api/chat", post(chat))
+        .route("/api/chat/stream", post(chat_stream))
+        .route("/api/chat/batch", post(batch::chat_batch))
+        .route("/api/chat/ws", get(ws::chat_ws))
+        .route("/api/chat/title", post(title::title))
+        .route("/api/generate", post(generate::generate))
+        .route("/v1/chat/completions", post(openai::chat_completions))
+        .route_layer(axum::middleware::from_fn(concurrency::retry_after))
+        .route_layer(axum::middleware::from_fn_with_state(state.clone(), ratelimit::limit));
+
+    // Everything but the health checks and metrics sits behind API_TOKEN when one is set.
+    let api = Router::new()
+        .merge(generation)
+        .route("/api/embeddings", post(embeddings::embeddings))
+        .route("/api/similarity", post(embeddings::similarity))
+        .route("/api/models", get(models::list_models))
+        .route("/api/ps", get(models::running_models))
+        .route("/api/version", get(health::version))
+        .route("/api/models/*name", get(models::show_model).delete(models::delete_model))
+        .route("/api/pull", post(pull::pull))
+        .route("/api/cancel/:id", post(cancel::cancel))
+        .route("/api/conversations", get(conversations::list).post(conversations::create))
+        .route("/api/conversations/:id", get(conversations::get))
+        .route("/api/conversations/:id/messages", post(conversations::append))
+        .route_layer(axum::middleware::from_fn_with_state(state.clone(), auth::require_token));
+
+    let app = Router::new()
+        .merge(api)
+        .route("/health", get(health::live))
+        .route("/health/ready", get(health::ready))
+        .route("/metrics", get(telemetry::render))
+        .fallback(fallback::not_found)
+        .layer(axum::middleware::from_fn(telemetry::track))
+        .layer(body_limit::layer(&state.config))
+        .layer(axum::middleware::from_fn_with_state(state.clone(), body_limit::explain_too_large))
+        .layer(axum::middleware::from_fn(envelope::wrap))
+        .layer(axum::middleware::from_fn(request_id::tag_errors))
+        // The default predicate skips text/event-stream, so SSE events still go out as they're
+        // produced instead of being held back by the encoder.
+        .layer(CompressionLayer::new())
+        .layer(cors)
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(request_id::make_span)
+                .on_response(DefaultOnResponse::new().level(Level::INFO).latency_unit(LatencyUnit::Millis)),
+        )
+        .layer(axum::middleware::from_fn_with_state(state.clone(), cors::reject_disallowed_preflight))
+        .layer(axum::middleware::from_fn(request_id::assign))
+        .with_state(state);
+    // axum only adds the Allow header to a 405 on the way out of a router, so the layer that
+    // explains 405s wraps the finished one, as does the /api/v2 rewrite, which has to happen
+    // before routing.
+    let app = Router::new()
+        .fallback_service(app)
+        .layer(axum::middleware::from_fn(fallback::explain_method_not_allowed))
+        .layer(axum::middleware::from_fn(envelope::strip_prefix));
+
+    let listener = match tokio::net::TcpListener::bind(addr).await {
+        Ok(l) => l,
+        Err(e) => {
+            tracing::error!("failed to bind {}: {}", addr, e);
+            std::process::exit(1);
+        }
+    };
+
+    // axum-server for HTTPS; it drains and cuts off connections itself once given the grace
+    // period.
+    if let Some(tls) = tls {
+        let listener = match listener.into_std() {
+            Ok(l) => l,
+            Err(e) => {
+                tracing::error!("failed to hand {} to the tls server: {}", addr, e);
+                std::process::exit(1);
             }
-            _ => None,
+        };
+        tracing::info!("Server running on {} (https)", addr);
+        let handle = axum_server::Handle::new();
+        let draining = handle.clone();
+        tokio::spawn(async move {
+            shutdown_signal().await;
+            tracing::info!("shutdown requested, draining in-flight requests for up to {}s", shutdown_grace.as_secs());
+            draining.graceful_shutdown(Some(shutdown_grace));
+            end_open_streams(shutdown_grace, &cancellations).await;
+        });
+        let server = axum_server::from_tcp_rustls(listener, tls).handle(handle);
+        if let Err(e) = server.serve(app.into_make_service_with_connect_info::<SocketAddr>()).await {
+            tracing::error!("server error: {}", e);
         }
+        tracing::info!("shutdown complete");
+        return;
     }
+    tracing::info!("Server running on {}", addr);
 
-    let content = extract_content(&json).unwrap_or_else(|| body_text.clone());
+    // Stop accepting on SIGINT/SIGTERM and let in-flight requests finish, but don't wait on
+    // long-lived streams forever.
+    let (stopping_tx, mut stopping_rx) = watch::channel(false);
+    let server = axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).with_graceful_shutdown(async move {
+        shutdown_signal().await;
+        tracing::info!("shutdown requested, draining in-flight requests for up to {}s", shutdown_grace.as_secs());
+        let _ = stopping_tx.send(true);
+        tokio::spawn(async move { end_open_streams(shutdown_grace, &cancellations).await });
+    });
+    tokio::select! {
+        res = server.into_future() => {
+            if let Err(e) = res {
+                tracing::error!("server error: {}", e);
+            }
+            tracing::info!("shutdown complete");
+        }
+        _ = async {
+            let _ = stopping_rx.wait_for(|stopping| *stopping).await;
+            tokio::time::sleep(shutdown_grace).await;
+        } => {
+            tracing::warn!("drain window elapsed, dropping remaining connections");
+        }
+    }
+}
 
-    Json(ChatResp { content })
+// Let open streams use most of the drain window to finish by themselves, then end the rest with
+// a `shutdown` event (and stop their Ollama requests), rather than have the cutoff drop them
+// mid-reply.
+async fn end_open_streams(grace: Duration, cancellations: &Cancellations) {
+    tokio::time::sleep(grace.saturating_sub(SHUTDOWN_NOTICE)).await;
+    cancellations.shut_down();
 }
 
-// Streaming endpoint: proxies Ollama's streaming response to the frontend.
-async fn chat_stream(Json(req): Json<ChatReq>) -> impl axum::response::IntoResponse {
-    // reuse normalization
-    fn normalize_model(opt: Option<String>) -> String {
-        match opt {
-            Some(m) => {
-                if m == "llama3.1" {
-                    return "llama3:8b".to_string();
-                }
-                if m.contains('.') && !m.contains(':') {
-                    return m.replace('.', ":");
-                }
-                m
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("failed to listen for ctrl-c: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sig) => {
+                sig.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
             }
-            None => "llama3:8b".to_string(),
         }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+#[derive(Deserialize)]
+struct ChatParams {
+    debug: Option<String>,
+}
+
+async fn chat(
+    State(state): State<AppState>,
+    Query(params): Query<ChatParams>,
+    headers: HeaderMap,
+    JsonBody(mut req): JsonBody<ChatReq>,
+) -> Result<Response, BackendError> {
+    let header = headers.get("x-debug").and_then(|v| v.to_str().ok());
+    req.debug = [params.debug.as_deref(), header].into_iter().flatten().any(|v| v == "true" || v == "1");
+    // `stream: true` gets the same SSE stream as /api/chat/stream, rather than being ignored.
+    if req.stream == Some(true) {
+        return chat_stream(State(state), headers, JsonBody(req)).await.map(IntoResponse::into_response);
+    }
+    // A retry with the same Idempotency-Key gets the first reply back instead of a second
+    // generation. The key is claimed before generating, so a repeat that arrives meanwhile
+    // can't start a second one either.
+    let in_flight = match idempotency::key(&headers, &req)?.zip(state.idempotent.as_ref()) {
+        Some(((key, fingerprint), replies)) => match replies.claim(key, fingerprint)? {
+            Claim::Replay(reply) => {
+                tracing::info!("replaying the reply for a repeated idempotency key");
+                let replayed = [(idempotency::REPLAYED_HEADER, "true")];
+                return Ok((replayed, timing_headers(&reply), Json(reply)).into_response());
+            }
+            Claim::Run(in_flight) => Some(in_flight),
+        },
+        None => None,
+    };
+    let Json(reply) = chat_reply(state, req).await?;
+    if let Some(in_flight) = in_flight {
+        in_flight.finish(reply.clone());
     }
+    Ok((timing_headers(&reply), Json(reply)).into_response())
+}
 
-    let model = normalize_model(req.model.clone());
-    println!("using model (stream): {}", model);
+// The whole reply in one JSON body.
+async fn chat_reply(state: AppState, mut req: ChatReq) -> Result<Json<ChatResp>, BackendError> {
+    if state.config.mock_mode {
+        return mock::chat(&state, req).await;
+    }
+    let started = Instant::now();
+    req.validate(&state.config)?;
+    let model = model::normalize_model(req.model.as_deref(), &state.config.aliases, &state.config.default_model);
+    model::check_allowed(&model, state.config.allowed_models.as_deref())?;
+    tracing::info!(%model, "chat request");
+    let dropped_messages = req.trim_context(&state.config);
+    bodies::log_messages(state.config.log_bodies, &req.messages);
+    conversations::check(&state, req.conversation_id.as_deref()).await?;
 
-    let body = serde_json::json!({
-        "model": model,
-        "messages": req.messages,
-        "stream": true
+    // The same deterministic request gets the same reply, so it may come from the cache.
+    // Debugging wants to see what Ollama says now, so it always goes upstream.
+    let options = req.options(&model, &state.config);
+    let cached = state.replies.clone().filter(|_| options.is_deterministic() && !req.debug).map(|cache| {
+        let key = reply_cache::key(&(&model, req.messages(&model, &state.config), &options, &req.format, &req.tools, req.think));
+        (cache, key)
     });
+    if let Some(hit) = cached.as_ref().and_then(|(cache, key)| cache.get(key)) {
+        tracing::debug!(%model, "reply served from the cache");
+        if let Some(id) = &req.conversation_id {
+            conversations::record(&state, id, &req.messages, hit.content.clone()).await;
+        }
+        return Ok(Json(hit));
+    }
 
-    let client = reqwest::Client::new();
-    let resp = match client.post("http://127.0.0.1:11434/api/chat").json(&body).send().await {
-        Ok(r) => r,
-        Err(e) => {
-            eprintln!("failed to send request to ollama (stream): {}", e);
-            // Return an SSE that immediately yields the error message as a single event.
-            let (tx_err, rx_err) = mpsc::channel::<Result<Event, std::convert::Infallible>>(1);
-            let _ = tx_err.send(Ok(Event::default().data(format!("Error contacting Ollama API: {}", e)))).await;
-            return Sse::new(ReceiverStream::new(rx_err));
+    let (resp, used_model) = cancel::log_if_abandoned(async {
+        let _permit = state.generations.acquire().await?;
+        with_fallbacks(&state, &model, |m| {
+            let body = req.ollama_request(&m, false, &state.config);
+            let state = &state;
+            async move { state.ollama().chat(&body).await }
+        })
+        .await
+    })
+    .await?;
+    telemetry::warn_if_slow(state.config.slow_request, started, &used_model, req.messages.len());
+    let mut content = resp.content().to_string();
+    let mut thinking = resp.message.thinking.clone().filter(|t| !t.is_empty());
+    if let Some(tag) = &state.config.think_tag {
+        let (inline, answer) = stream::split_thinking(tag, &content);
+        if !inline.is_empty() {
+            thinking.get_or_insert_with(String::new).push_str(&inline);
         }
+        content = answer;
+    }
+    bodies::log_reply(state.config.log_bodies, &content);
+    let mut done_reason = resp.done_reason;
+    if truncate_output(&mut content, resp.eval_count, state.config.max_output_tokens) {
+        tracing::warn!(%model, "reply ran past the server output limit, truncated");
+        done_reason = Some("server_limit".to_string());
+    }
+    // JSON mode is a strong nudge, not a guarantee; don't hand back output the client can't parse.
+    if req.wants_json() && serde_json::from_str::<Value>(&content).is_err() {
+        tracing::warn!(%model, "model output is not valid json despite format \"json\"");
+        return Err(bad_gateway(stream::INVALID_JSON_MESSAGE.to_string(), None));
+    }
+    if let Some(id) = &req.conversation_id {
+        conversations::record(&state, id, &req.messages, content.clone()).await;
+    }
+
+    let reply = ChatResp {
+        content,
+        model,
+        prompt_tokens: resp.prompt_eval_count,
+        completion_tokens: resp.eval_count,
+        // Ollama reports durations in nanoseconds.
+        total_duration_ms: resp.total_duration.map(|ns| ns / 1_000_000),
+        done_reason,
+        used_model,
+        dropped_messages,
+        tool_calls: resp.message.tool_calls,
+        thinking,
+        raw: req.debug.then_some(resp.raw),
+        timings: Timings {
+            load_ms: resp.load_duration.map(|ns| ns / 1_000_000),
+            prompt_eval_ms: resp.prompt_eval_duration.map(|ns| ns / 1_000_000),
+            eval_ms: resp.eval_duration.map(|ns| ns / 1_000_000),
+        },
     };
+    if let Some((cache, key)) = cached {
+        cache.insert(key, reply.clone());
+    }
+    Ok(Json(reply))
+}
 
-    if !resp.status().is_success() {
-        let txt = match resp.text().await {
-            Ok(t) => t,
-            Err(_) => String::from("unknown error from ollama"),
-        };
-        // Return an SSE that immediately yields the error message as a single event.
-        let (tx_err, rx_err) = mpsc::channel::<Result<Event, std::convert::Infallible>>(1);
-        let _ = tx_err.send(Ok(Event::default().data(txt))).await;
-        return Sse::new(ReceiverStream::new(rx_err));
-    }
-
-    // Create an mpsc channel and spawn a task that forwards bytes from reqwest into the channel as SSE events.
-    let (tx, rx) = mpsc::channel::<Result<Event, std::convert::Infallible>>(16);
-    let mut remote_stream = resp.bytes_stream();
-
-    tokio::spawn(async move {
-        while let Some(chunk) = remote_stream.try_next().await.transpose() {
-            match chunk {
-                Ok(bytes) => {
-                    let s = match String::from_utf8(bytes.to_vec()) {
-                        Ok(t) => t,
-                        Err(_) => "".to_string(),
-                    };
-                    // Send as data event
-                    if tx.send(Ok(Event::default().data(s))).await.is_err() {
-                        break;
+// Run `call` for `model`, moving down the MODEL_FALLBACKS chain while Ollama fails to load the
+// model (e.g. out of GPU memory). Any other error is returned as is. Gives back the result and
+// the model that produced it.
+async fn with_fallbacks<T, F, Fut>(state: &AppState, model: &str, mut call: F) -> Result<(T, String), BackendError>
+where
+    F: FnMut(String) -> Fut,
+    Fut: Future<Output = Result<T, BackendError>>,
+{
+    let mut last_err = None;
+    for candidate in model::fallback_chain(model, &state.config.model_fallbacks) {
+        match call(candidate.clone()).await {
+            Ok(v) => return Ok((v, candidate)),
+            Err(e) if models::is_load_failure(&e) => {
+                tracing::warn!(model = %candidate, error = %e.message(), "model failed to load, trying the next fallback");
+                last_err = Some(e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Err(last_err.expect("the chain always starts with the requested model"))
+}
+
+// Streaming endpoint: proxies Ollama's streaming response to the frontend.
+async fn chat_stream(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    JsonBody(mut req): JsonBody<ChatReq>,
+) -> Result<Sse<stream::Events>, BackendError> {
+    // A client reconnecting with Last-Event-ID gets the rest of its generation, not a new one.
+    if let Some(resumable) = &state.resumable {
+        if let Some(rx) = resumable.resume(&headers).await? {
+            return Ok(stream::sse(rx, state.config.sse_keep_alive, state.config.sse_retry));
+        }
+    }
+    if state.config.mock_mode {
+        return mock::chat_stream(state, req).await;
+    }
+    let prepared = prepare_chat_stream(&state, &mut req).await?;
+    let (tx, rx) = mpsc::channel::<SseItem>(stream::CHANNEL_CAPACITY);
+    let rx = stream::paced(rx, req.token_interval());
+    match state.generations.admit()? {
+        Admission::Ready(permit) => {
+            let opened = match open_chat_stream(&state, &req, prepared, permit).await {
+                Ok(opened) => opened,
+                Err(e) if e.is_client_error() => return Err(e),
+                Err(e) => return Ok(stream::single_event(stream::error_event(&e.message()))),
+            };
+            forward_chat_stream(&state, req, opened, tx);
+        }
+        // Start the response now, so the client can show its place in line while it waits.
+        // From here on failures can only be reported as error events.
+        Admission::Queued(waiter) => {
+            let state = state.clone();
+            let queued = async move {
+                let queued_event = |position| {
+                    let data = serde_json::json!({ "position": position }).to_string();
+                    Ok(Event::default().event("queued").data(data))
+                };
+                let opened = match waiter.wait_reporting(&tx, queued_event).await {
+                    Some(Ok(permit)) => open_chat_stream(&state, &req, prepared, permit).await,
+                    Some(Err(e)) => Err(e),
+                    None => {
+                        tracing::info!("client went away while queued");
+                        return;
+                    }
+                };
+                match opened {
+                    Ok(opened) => forward_chat_stream(&state, req, opened, tx),
+                    Err(e) => {
+                        let _ = tx.send(Ok(stream::error_event(&e.message()))).await;
                     }
                 }
-                Err(e) => {
-                    let _ = tx.send(Ok(Event::default().data(format!("__ERR__:{}", e)))).await;
-                    break;
-                }
-            }
+            };
+            tokio::spawn(queued.instrument(tracing::Span::current()));
         }
-    });
+    }
+    Ok(stream::sse(rx, state.config.sse_keep_alive, state.config.sse_retry))
+}
+
+// Announce the model and generation id, then stream the reply into `tx`.
+fn forward_chat_stream(state: &AppState, req: ChatReq, opened: OpenedStream, tx: mpsc::Sender<SseItem>) {
+    // An id the client can pass to /api/cancel to stop this generation explicitly.
+    let registration = state.cancellations.register();
+    let tx = match &state.resumable {
+        Some(resumable) => resumable.relay(registration.id.clone(), tx),
+        None => tx,
+    };
+    // Tell the client up front which model is answering, after aliases and fallbacks, and
+    // how much of the conversation was left out to fit.
+    let announce =
+        serde_json::json!({ "model": opened.used_model, "dropped_messages": opened.dropped_messages }).to_string();
+    let _ = tx.try_send(Ok(Event::default().event("model").data(announce)));
+    let generation = serde_json::json!({ "id": registration.id }).to_string();
+    let _ = tx.try_send(Ok(Event::default().event("generation").data(generation)));
+    let idle_timeout = state.config.stream_idle_timeout;
+    let messages = req.messages.len();
+    let json_reply = req.wants_json();
+    let encode = stream::with_complete(stream::native_encoder(state.config.sse_text_deltas, json_reply));
+    let encode = conversations::record_stream(state.clone(), req.conversation_id, req.messages, encode);
+    let encode = stream::with_json_check(json_reply, encode);
+    let encode = stream::with_stats(opened.started, encode);
+    let encode = bodies::log_stream(state.config.log_bodies, encode);
+    let encode = telemetry::log_slow_stream(state.config.slow_request, opened.started, opened.used_model, messages, encode);
+    let encode = stream::with_output_cap(state.config.max_output_tokens, encode);
+    let encode = stream::with_think_tags(state.config.think_tag.as_deref(), encode);
+    stream::spawn_forwarder(opened.resp, tx, opened.permit, Some(registration), idle_timeout, encode);
+}
+
+// A chat stream that has been checked and trimmed, but not yet given a generation slot.
+struct PreparedStream {
+    model: String,
+    dropped_messages: usize,
+    // When the request came in, for SLOW_REQUEST_MS.
+    started: Instant,
+}
+
+// A chat stream that Ollama has started answering.
+struct OpenedStream {
+    resp: reqwest::Response,
+    // The model answering, after aliases and fallbacks.
+    used_model: String,
+    // Held until the stream ends.
+    permit: tokio::sync::OwnedSemaphorePermit,
+    dropped_messages: usize,
+    started: Instant,
+}
+
+// Everything a streaming chat needs before it takes a generation slot, shared by the SSE and
+// WebSocket routes: validate, resolve the model and trim to the context budget.
+async fn prepare_chat_stream(state: &AppState, req: &mut ChatReq) -> Result<PreparedStream, BackendError> {
+    let started = Instant::now();
+    req.validate(&state.config)?;
+    let model = model::normalize_model(req.model.as_deref(), &state.config.aliases, &state.config.default_model);
+    model::check_allowed(&model, state.config.allowed_models.as_deref())?;
+    tracing::info!(%model, "chat stream request");
+    let dropped_messages = req.trim_context(&state.config);
+    bodies::log_messages(state.config.log_bodies, &req.messages);
+    conversations::check(state, req.conversation_id.as_deref()).await?;
+    Ok(PreparedStream { model, dropped_messages, started })
+}
 
-    let stream = ReceiverStream::new(rx);
-    Sse::new(stream)
+// Open the upstream stream (with fallbacks) once a slot is held.
+async fn open_chat_stream(
+    state: &AppState,
+    req: &ChatReq,
+    prepared: PreparedStream,
+    permit: tokio::sync::OwnedSemaphorePermit,
+) -> Result<OpenedStream, BackendError> {
+    let (resp, used_model) = with_fallbacks(state, &prepared.model, |m| {
+        let body = req.ollama_request(&m, true, &state.config);
+        async move { state.ollama().chat_stream(&body).await }
+    })
+    .await?;
+    Ok(OpenedStream { resp, used_model, permit, dropped_messages: prepared.dropped_messages, started: prepared.started })
 }
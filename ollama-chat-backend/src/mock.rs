@@ -0,0 +1,127 @@
+use std::time::{Duration, Instant};
+
+use axum::{
+    response::sse::{Event, Sse},
+    Json,
+};
+use tokio::sync::mpsc;
+use tracing::Instrument;
+
+use crate::stream::{self, DoneStats, SseItem, StreamItem};
+use crate::{conversations, AppState, BackendError, ChatReq, ChatResp, Msg};
+
+// MOCK_MODE: canned replies for working on the frontend without Ollama or a GPU. Nothing here
+// contacts Ollama.
+
+pub const MODEL: &str = "mock";
+
+// Roughly what a small local model feels like: a pause before the first token, then a steady
+// trickle.
+const FIRST_TOKEN_DELAY: Duration = Duration::from_millis(400);
+const TOKEN_DELAY: Duration = Duration::from_millis(40);
+
+// Echo the last user message back, so it's obvious which request a reply belongs to.
+fn reply(messages: &[Msg]) -> String {
+    match messages.iter().rev().find(|m| m.role == "user") {
+        Some(m) => format!("This is a mock reply; Ollama was not contacted. You said: {}", m.content.trim()),
+        None => "This is a mock reply; Ollama was not contacted.".to_string(),
+    }
+}
+
+// The mock counterpart of POST /api/chat.
+pub async fn chat(state: &AppState, mut req: ChatReq) -> Result<Json<ChatResp>, BackendError> {
+    req.validate(&state.config)?;
+    conversations::check(state, req.conversation_id.as_deref()).await?;
+    let content = reply(&req.messages);
+    let tokens = tokens(&content).len() as u64;
+    let elapsed = FIRST_TOKEN_DELAY + TOKEN_DELAY * tokens as u32;
+    tokio::time::sleep(elapsed).await;
+    if let Some(id) = &req.conversation_id {
+        conversations::record(state, id, &req.messages, content.clone()).await;
+    }
+    Ok(Json(ChatResp {
+        content,
+        model: MODEL.to_string(),
+        prompt_tokens: None,
+        completion_tokens: Some(tokens),
+        total_duration_ms: Some(elapsed.as_millis() as u64),
+        done_reason: Some("stop".to_string()),
+        used_model: MODEL.to_string(),
+        dropped_messages: 0,
+        tool_calls: None,
+        thinking: None,
+        raw: None,
+        timings: Default::default(),
+    }))
+}
+
+// The mock counterpart of POST /api/chat/stream, with the same events minus `generation`.
+pub async fn chat_stream(state: AppState, mut req: ChatReq) -> Result<Sse<stream::Events>, BackendError> {
+    let started = Instant::now();
+    check_stream(&state, &mut req).await?;
+    let (tx, rx) = mpsc::channel::<SseItem>(stream::CHANNEL_CAPACITY);
+    let rx = stream::paced(rx, req.token_interval());
+    let announce = serde_json::json!({ "model": MODEL }).to_string();
+    let _ = tx.try_send(Ok(Event::default().event("model").data(announce)));
+    let encode = stream::with_complete(stream::native_encoder(state.config.sse_text_deltas, false));
+    let mut encode = stream::with_stats(started, encode);
+    spawn_reply(&state, req, tx, move |item| {
+        let (events, keep_going) = encode(item);
+        (events.into_iter().map(Ok).collect(), keep_going)
+    });
+    Ok(stream::sse(rx, state.config.sse_keep_alive, state.config.sse_retry))
+}
+
+// What a mock stream checks before it starts, on the SSE and WebSocket routes alike.
+pub async fn check_stream(state: &AppState, req: &mut ChatReq) -> Result<(), BackendError> {
+    req.validate(&state.config)?;
+    conversations::check(state, req.conversation_id.as_deref()).await
+}
+
+// Stream the mock reply to `req` through `encode`, storing it in the request's conversation.
+pub fn spawn_reply<M, F>(state: &AppState, req: ChatReq, tx: mpsc::Sender<M>, encode: F)
+where
+    M: Send + 'static,
+    F: FnMut(StreamItem) -> (Vec<M>, bool) + Send + 'static,
+{
+    let content = reply(&req.messages);
+    let encode = conversations::record_stream(state.clone(), req.conversation_id, req.messages, encode);
+    spawn_stream(content, tx, encode);
+}
+
+// Play `content` through `encode` a word at a time, as if Ollama were streaming it.
+fn spawn_stream<M, F>(content: String, tx: mpsc::Sender<M>, mut encode: F)
+where
+    M: Send + 'static,
+    F: FnMut(StreamItem) -> (Vec<M>, bool) + Send + 'static,
+{
+    tokio::spawn(
+        async move {
+            tokio::time::sleep(FIRST_TOKEN_DELAY).await;
+            let tokens = tokens(&content);
+            let stats = DoneStats {
+                done_reason: Some("stop".to_string()),
+                eval_count: Some(tokens.len() as u64),
+                ..Default::default()
+            };
+            let items = tokens.into_iter().map(|t| StreamItem::Delta(t.to_string()));
+            for item in items.chain(std::iter::once(StreamItem::Done(stats))) {
+                let (messages, keep_going) = encode(item);
+                for message in messages {
+                    if tx.send(message).await.is_err() {
+                        return;
+                    }
+                }
+                if !keep_going {
+                    return;
+                }
+                tokio::time::sleep(TOKEN_DELAY).await;
+            }
+        }
+        .in_current_span(),
+    );
+}
+
+fn tokens(content: &str) -> Vec<&str> {
+    content.split_inclusive(' ').collect()
+}
@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::BackendError;
+
+// Used when a request names no model and DEFAULT_MODEL is unset.
+pub const DEFAULT_MODEL: &str = "llama3:8b";
+
+// Maps model names clients send to the names Ollama knows them by.
+#[derive(Clone, Debug, Default)]
+pub struct ModelAliases {
+    map: HashMap<String, String>,
+}
+
+impl ModelAliases {
+    // Load a flat TOML table of `"alias" = "ollama:name"` pairs. A missing file is only an error
+    // when the caller says it must exist.
+    pub fn load(path: &Path, required: bool) -> Result<ModelAliases, String> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(t) => t,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && !required => {
+                return Ok(ModelAliases::default());
+            }
+            Err(e) => return Err(format!("failed to read {}: {}", path.display(), e)),
+        };
+        let map: HashMap<String, String> = toml::from_str(&text)
+            .map_err(|e| format!("invalid alias file {}: {}", path.display(), e))?;
+        Ok(ModelAliases { map })
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+}
+
+// Resolve the model a request asked for, or `default` when it named none. Names without an
+// alias pass through untouched.
+pub fn normalize_model(requested: Option<&str>, aliases: &ModelAliases, default: &str) -> String {
+    let m = requested.unwrap_or(default);
+    aliases.map.get(m).cloned().unwrap_or_else(|| m.to_string())
+}
+
+// Refuse a model that ALLOWED_MODELS leaves out; everything is allowed without a list. A name
+// without a tag matches the same name with ":latest", as it does in Ollama.
+pub fn check_allowed(model: &str, allowed: Option<&[String]>) -> Result<(), BackendError> {
+    let Some(allowed) = allowed else {
+        return Ok(());
+    };
+    let tagged = |name: &str| if name.contains(':') { name.to_string() } else { format!("{}:latest", name) };
+    if allowed.iter().any(|a| tagged(a) == tagged(model)) {
+        return Ok(());
+    }
+    Err(BackendError::Forbidden(format!(
+        "model \"{}\" is not allowed on this server; use one of: {}",
+        model,
+        allowed.join(", ")
+    )))
+}
+
+// The models to try for a request, in order: the one asked for, then what follows it in the
+// fallback list, or the whole list when it isn't in there.
+pub fn fallback_chain(primary: &str, fallbacks: &[String]) -> Vec<String> {
+    let rest = match fallbacks.iter().position(|f| f == primary) {
+        Some(i) => &fallbacks[i + 1..],
+        None => fallbacks,
+    };
+    std::iter::once(primary.to_string()).chain(rest.iter().filter(|f| *f != primary).cloned()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn aliases(pairs: &[(&str, &str)]) -> ModelAliases {
+        let map = pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        ModelAliases { map }
+    }
+
+    #[test]
+    fn resolves_llama31_alias_from_shipped_file() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("aliases.toml");
+        let aliases = ModelAliases::load(&path, true).unwrap();
+        assert_eq!(normalize_model(Some("llama3.1"), &aliases, DEFAULT_MODEL), "llama3:8b");
+    }
+
+    // The old dot-to-colon guess turned real tags like "qwen2.5" into names Ollama doesn't
+    // have; dotted names now only change through an explicit alias.
+    #[test]
+    fn keeps_dotted_names_without_an_alias() {
+        let aliases = aliases(&[("llama3.1", "llama3:8b")]);
+        assert_eq!(normalize_model(Some("qwen2.5"), &aliases, DEFAULT_MODEL), "qwen2.5");
+        assert_eq!(normalize_model(Some("llama3.1"), &aliases, DEFAULT_MODEL), "llama3:8b");
+    }
+
+    #[test]
+    fn passes_names_with_a_tag_through() {
+        let aliases = aliases(&[("llama3.1", "llama3:8b")]);
+        assert_eq!(normalize_model(Some("mistral:7b"), &aliases, DEFAULT_MODEL), "mistral:7b");
+        assert_eq!(normalize_model(Some("llama3.1:70b"), &aliases, DEFAULT_MODEL), "llama3.1:70b");
+    }
+
+    #[test]
+    fn defaults_when_no_model_is_given() {
+        assert_eq!(normalize_model(None, &ModelAliases::default(), DEFAULT_MODEL), DEFAULT_MODEL);
+        assert_eq!(normalize_model(None, &ModelAliases::default(), "mistral"), "mistral");
+    }
+
+    #[test]
+    fn resolves_an_aliased_default() {
+        let aliases = aliases(&[("llama3.1", "llama3:8b")]);
+        assert_eq!(normalize_model(None, &aliases, "llama3.1"), "llama3:8b");
+    }
+
+    #[test]
+    fn allows_only_listed_models() {
+        let allowed = vec!["llama3:8b".to_string(), "mistral".to_string()];
+        assert!(check_allowed("llama3:8b", Some(&allowed)).is_ok());
+        assert!(check_allowed("mistral:latest", Some(&allowed)).is_ok());
+        assert!(check_allowed("llama3:70b", Some(&allowed)).is_err());
+        assert!(check_allowed("llama3:70b", None).is_ok());
+    }
+
+    #[test]
+    fn fallback_chain_continues_after_the_requested_model() {
+        let fallbacks = vec!["llama3:70b".to_string(), "llama3:8b".to_string()];
+        assert_eq!(fallback_chain("llama3:70b", &fallbacks), ["llama3:70b", "llama3:8b"]);
+        assert_eq!(fallback_chain("llama3:8b", &fallbacks), ["llama3:8b"]);
+        assert_eq!(fallback_chain("mistral", &fallbacks), ["mistral", "llama3:70b", "llama3:8b"]);
+        assert_eq!(fallback_chain("mistral", &[]), ["mistral"]);
+    }
+}
@@ -0,0 +1,313 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{bad_gateway, bodies, mock, ollama, upstream_failure, AppState, BackendError};
+
+// How long a fetched list of installed models is reused for "model not found" errors.
+const TAG_CACHE_TTL: Duration = Duration::from_secs(30);
+
+#[derive(Deserialize)]
+struct TagsResp {
+    #[serde(default)]
+    models: Vec<TagEntry>,
+}
+
+#[derive(Deserialize)]
+struct TagEntry {
+    name: String,
+    #[serde(default)]
+    size: u64,
+    #[serde(default)]
+    modified_at: String,
+    details: Option<TagDetails>,
+}
+
+#[derive(Deserialize)]
+struct TagDetails {
+    parameter_size: Option<String>,
+}
+
+// What the frontend model picker needs, trimmed from Ollama's /api/tags entry.
+#[derive(Serialize)]
+pub struct ModelInfo {
+    name: String,
+    size: u64,
+    modified_at: String,
+    parameter_size: Option<String>,
+}
+
+// Lists locally installed models by proxying Ollama's /api/tags.
+pub async fn list_models(State(state): State<AppState>) -> Result<Json<Vec<ModelInfo>>, BackendError> {
+    if state.config.mock_mode {
+        let mock = ModelInfo { name: mock::MODEL.to_string(), size: 0, modified_at: String::new(), parameter_size: None };
+        return Ok(Json(vec![mock]));
+    }
+    let models = fetch_tags(&state)
+        .await?
+        .into_iter()
+        .map(|m| ModelInfo {
+            name: m.name,
+            size: m.size,
+            modified_at: m.modified_at,
+            parameter_size: m.details.and_then(|d| d.parameter_size),
+        })
+        .collect();
+    Ok(Json(models))
+}
+
+#[derive(Deserialize)]
+struct PsResp {
+    #[serde(default)]
+    models: Vec<PsEntry>,
+}
+
+#[derive(Deserialize)]
+struct PsEntry {
+    name: String,
+    #[serde(default)]
+    size: u64,
+    #[serde(default)]
+    size_vram: u64,
+    #[serde(default)]
+    expires_at: String,
+}
+
+// A model Ollama has loaded, from its /api/ps entry. Sizes are in bytes; `expires_at` is when
+// it will be unloaded unless used again.
+#[derive(Serialize)]
+pub struct RunningModel {
+    name: String,
+    size: u64,
+    size_vram: u64,
+    expires_at: String,
+}
+
+// GET /api/ps: the models currently loaded in memory, so a client can tell whether a request
+// will hit a cold start.
+pub async fn running_models(State(state): State<AppState>) -> Result<Json<Vec<RunningModel>>, BackendError> {
+    if state.config.mock_mode {
+        return Ok(Json(Vec::new()));
+    }
+    let timeout = state.config.request_timeout;
+    let resp = match state.client.get(state.upstreams.pick().url("/api/ps")).timeout(timeout).send().await {
+        Ok(r) => r,
+        Err(e) => {
+            tracing::error!(error = %e, "failed to list running ollama models");
+            return Err(upstream_failure("Error contacting Ollama API", e, timeout));
+        }
+    };
+
+    let status = resp.status();
+    if !status.is_success() {
+        let txt = resp.text().await.unwrap_or_default();
+        let shown = bodies::shown(state.config.log_bodies, &txt);
+        tracing::warn!(%status, body = %shown, "ollama returned an error listing running models");
+        return Err(bad_gateway(ollama::error_text(&txt), Some(status.as_u16())));
+    }
+
+    let ps = match resp.json::<PsResp>().await {
+        Ok(ps) => ps,
+        Err(e) => {
+            tracing::error!(error = %e, "invalid running model list from ollama");
+            return Err(upstream_failure("Invalid running model list from Ollama", e, timeout));
+        }
+    };
+    let models = ps
+        .models
+        .into_iter()
+        .map(|m| RunningModel { name: m.name, size: m.size, size_vram: m.size_vram, expires_at: m.expires_at })
+        .collect();
+    Ok(Json(models))
+}
+
+// What the model picker shows about one model, from Ollama's /api/show.
+#[derive(Serialize)]
+pub struct ModelDetails {
+    name: String,
+    modelfile: String,
+    parameters: String,
+    template: String,
+    // Family, format, parameter_size, quantization_level and so on, as Ollama reports them.
+    details: Value,
+    // The longest context the model was trained for, if Ollama says.
+    context_length: Option<u64>,
+}
+
+// GET /api/models/*name: metadata for an installed model via Ollama's /api/show.
+pub async fn show_model(State(state): State<AppState>, Path(name): Path<String>) -> Result<Json<ModelDetails>, BackendError> {
+    if state.config.mock_mode && name == mock::MODEL {
+        return Ok(Json(ModelDetails {
+            name,
+            modelfile: String::new(),
+            parameters: String::new(),
+            template: String::new(),
+            details: Value::Object(Default::default()),
+            context_length: None,
+        }));
+    }
+    let show = state.ollama().show(&name).await?;
+    let context_length = show
+        .model_info
+        .iter()
+        .find(|(key, _)| key.ends_with(".context_length"))
+        .and_then(|(_, v)| v.as_u64());
+    Ok(Json(ModelDetails {
+        name,
+        modelfile: show.modelfile,
+        parameters: show.parameters,
+        template: show.template,
+        details: show.details,
+        context_length,
+    }))
+}
+
+// Pulls and deletes change one instance's models. With OLLAMA_HOSTS that would leave the
+// instances with different models installed, so they're refused there.
+pub fn check_single_instance(state: &AppState, action: &str) -> Result<(), BackendError> {
+    if state.config.ollama_hosts.len() > 1 {
+        let message = format!("{} models is not supported with several OLLAMA_HOSTS; manage each instance directly", action);
+        return Err(BackendError::Unsupported(message));
+    }
+    Ok(())
+}
+
+// DELETE /api/models/*name: remove an installed model via Ollama's /api/delete.
+pub async fn delete_model(State(state): State<AppState>, Path(name): Path<String>) -> Result<StatusCode, BackendError> {
+    tracing::info!(model = %name, "delete model request");
+    check_single_instance(&state, "deleting")?;
+    let timeout = state.config.request_timeout;
+    let resp = match state
+        .client
+        .delete(state.upstreams.pick().url("/api/delete"))
+        .timeout(timeout)
+        .json(&serde_json::json!({ "model": name }))
+        .send()
+        .await
+    {
+        Ok(r) => r,
+        Err(e) => {
+            tracing::error!(error = %e, "failed to delete ollama model");
+            return Err(upstream_failure("Error contacting Ollama API", e, timeout));
+        }
+    };
+
+    let status = resp.status();
+    if !status.is_success() {
+        let txt = resp.text().await.unwrap_or_default();
+        let shown = bodies::shown(state.config.log_bodies, &txt);
+        tracing::warn!(%status, body = %shown, "ollama returned an error deleting a model");
+        if status == reqwest::StatusCode::NOT_FOUND {
+            return Err(BackendError::ModelNotFound { model: name, available_models: None, hint: None });
+        }
+        return Err(bad_gateway(ollama::error_text(&txt), Some(status.as_u16())));
+    }
+    state.tags.clear();
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn fetch_tags(state: &AppState) -> Result<Vec<TagEntry>, BackendError> {
+    let timeout = state.config.request_timeout;
+    let resp = match state
+        .client
+        .get(state.upstreams.pick().url("/api/tags"))
+        .timeout(timeout)
+        .send()
+        .await
+    {
+        Ok(r) => r,
+        Err(e) => {
+            tracing::error!(error = %e, "failed to list ollama models");
+            return Err(upstream_failure("Error contacting Ollama API", e, timeout));
+        }
+    };
+
+    let status = resp.status();
+    if !status.is_success() {
+        let txt = resp.text().await.unwrap_or_default();
+        let shown = bodies::shown(state.config.log_bodies, &txt);
+        tracing::warn!(%status, body = %shown, "ollama returned an error listing models");
+        return Err(bad_gateway(ollama::error_text(&txt), Some(status.as_u16())));
+    }
+
+    match resp.json::<TagsResp>().await {
+        Ok(t) => Ok(t.models),
+        Err(e) => {
+            tracing::error!(error = %e, "invalid tag list from ollama");
+            Err(upstream_failure("Invalid tag list from Ollama", e, timeout))
+        }
+    }
+}
+
+// Installed model names, refetched at most every TAG_CACHE_TTL.
+#[derive(Default)]
+pub struct TagCache {
+    entry: Mutex<Option<(Instant, Vec<String>)>>,
+}
+
+impl TagCache {
+    fn get(&self) -> Option<Vec<String>> {
+        match &*self.entry.lock().unwrap() {
+            Some((fetched, names)) if fetched.elapsed() < TAG_CACHE_TTL => Some(names.clone()),
+            _ => None,
+        }
+    }
+
+    fn set(&self, names: Vec<String>) {
+        *self.entry.lock().unwrap() = Some((Instant::now(), names));
+    }
+
+    fn clear(&self) {
+        *self.entry.lock().unwrap() = None;
+    }
+}
+
+async fn installed_names(state: &AppState) -> Option<Vec<String>> {
+    if let Some(names) = state.tags.get() {
+        return Some(names);
+    }
+    let names: Vec<String> = fetch_tags(state).await.ok()?.into_iter().map(|m| m.name).collect();
+    state.tags.set(names.clone());
+    Some(names)
+}
+
+// Ollama answers a request for a model that isn't installed with
+// 404 `model "x" not found, try pulling it first`.
+pub fn is_model_not_found(status: reqwest::StatusCode, error: &str) -> bool {
+    status == reqwest::StatusCode::NOT_FOUND && error.contains("not found")
+}
+
+// Ollama reports a model it couldn't load (too big for the GPU or RAM, or a crashed runner)
+// as a 500 with one of these in the message.
+const LOAD_FAILURE_MARKERS: &[&str] = &[
+    "out of memory",
+    "requires more system memory",
+    "unable to load model",
+    "failed to load model",
+    "llama runner process has terminated",
+];
+
+pub fn is_load_failure(e: &BackendError) -> bool {
+    let BackendError::UpstreamStatus { error, status: Some(status) } = e else {
+        return false;
+    };
+    let error = error.to_lowercase();
+    *status >= 500 && LOAD_FAILURE_MARKERS.iter().any(|m| error.contains(m))
+}
+
+// A 404 telling the client which models it could use instead. If the tag list can't be
+// fetched the error is still returned, just without the list.
+pub async fn model_not_found(state: &AppState, model: &str) -> BackendError {
+    BackendError::ModelNotFound {
+        model: model.to_string(),
+        available_models: installed_names(state).await,
+        hint: Some(format!("run `ollama pull {}` to download it", model)),
+    }
+}
@@ -0,0 +1,341 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::http::StatusCode;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{bad_gateway, bodies, models, telemetry, upstream_failure, AppState, BackendError, Msg};
+
+// Typed requests and replies for the parts of Ollama's HTTP API the generation routes use, and
+// the client that sends them.
+
+// Body for Ollama's /api/chat.
+#[derive(Serialize)]
+pub struct OllamaChatRequest {
+    pub model: String,
+    pub messages: Vec<Msg>,
+    pub stream: bool,
+    #[serde(skip_serializing_if = "OllamaOptions::is_empty")]
+    pub options: OllamaOptions,
+    // "json" or a JSON schema.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tools: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub think: Option<bool>,
+    // A number of seconds or a duration string like "5m".
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keep_alive: Option<Value>,
+}
+
+// Body for Ollama's /api/generate.
+#[derive(Serialize)]
+pub struct OllamaGenerateRequest {
+    pub model: String,
+    pub prompt: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suffix: Option<String>,
+    pub stream: bool,
+    #[serde(skip_serializing_if = "OllamaOptions::is_empty")]
+    pub options: OllamaOptions,
+}
+
+// Per-request model options; anything left unset keeps the model's default. Also the shape of
+// one model's entry in MODEL_PRESETS_FILE.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct OllamaOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+    // Most tokens to generate; negative means no limit.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub num_predict: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub num_ctx: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub num_gpu: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub num_thread: Option<i32>,
+}
+
+impl OllamaOptions {
+    fn is_empty(&self) -> bool {
+        *self == OllamaOptions::default()
+    }
+
+    // Each option from `self`, or from `fallback` where `self` leaves it unset.
+    pub fn or(self, fallback: OllamaOptions) -> OllamaOptions {
+        OllamaOptions {
+            temperature: self.temperature.or(fallback.temperature),
+            top_p: self.top_p.or(fallback.top_p),
+            seed: self.seed.or(fallback.seed),
+            num_predict: self.num_predict.or(fallback.num_predict),
+            num_ctx: self.num_ctx.or(fallback.num_ctx),
+            stop: self.stop.or(fallback.stop),
+            num_gpu: self.num_gpu.or(fallback.num_gpu),
+            num_thread: self.num_thread.or(fallback.num_thread),
+        }
+    }
+
+    // Whether the same request always gets the same reply: greedy sampling or a pinned seed.
+    pub fn is_deterministic(&self) -> bool {
+        self.temperature == Some(0.0) || self.seed.is_some()
+    }
+}
+
+// Ollama's non-streaming /api/chat reply. Durations are in nanoseconds.
+#[derive(Deserialize)]
+pub struct OllamaChatResponse {
+    pub message: OllamaMessage,
+    pub done_reason: Option<String>,
+    pub prompt_eval_count: Option<u64>,
+    pub eval_count: Option<u64>,
+    pub total_duration: Option<u64>,
+    pub load_duration: Option<u64>,
+    pub prompt_eval_duration: Option<u64>,
+    pub eval_duration: Option<u64>,
+    // The whole reply as Ollama sent it, for `?debug=true`.
+    #[serde(skip)]
+    pub raw: Value,
+}
+
+#[derive(Deserialize)]
+pub struct OllamaMessage {
+    pub content: String,
+    #[serde(default)]
+    pub tool_calls: Option<Value>,
+    // Reasoning, from thinking models asked to `think`.
+    #[serde(default)]
+    pub thinking: Option<String>,
+}
+
+impl OllamaChatResponse {
+    // The reply text.
+    pub fn content(&self) -> &str {
+        &self.message.content
+    }
+}
+
+// Ollama's non-streaming /api/generate reply.
+#[derive(Deserialize)]
+pub struct OllamaGenerateResponse {
+    pub response: String,
+}
+
+// Ollama's /api/show reply, less the tensor listing and license text.
+#[derive(Deserialize)]
+pub struct OllamaShowResponse {
+    #[serde(default)]
+    pub modelfile: String,
+    #[serde(default)]
+    pub parameters: String,
+    #[serde(default)]
+    pub template: String,
+    #[serde(default)]
+    pub details: Value,
+    // Architecture facts keyed by family, e.g. "llama.context_length".
+    #[serde(default)]
+    pub model_info: serde_json::Map<String, Value>,
+}
+
+// Calls to Ollama on behalf of a request, with retries, error mapping and token accounting.
+pub struct Client<'a> {
+    state: &'a AppState,
+}
+
+impl AppState {
+    pub fn ollama(&self) -> Client<'_> {
+        Client { state: self }
+    }
+}
+
+impl Client<'_> {
+    pub async fn chat(&self, req: &OllamaChatRequest) -> Result<OllamaChatResponse, BackendError> {
+        let json = self.post("/api/chat", &to_body(req)).await?;
+        let mut resp: OllamaChatResponse = self.parse("/api/chat", &json)?;
+        resp.raw = json;
+        Ok(resp)
+    }
+
+    // Start a streaming chat; the body is Ollama's NDJSON, for `stream::spawn_forwarder`.
+    pub async fn chat_stream(&self, req: &OllamaChatRequest) -> Result<reqwest::Response, BackendError> {
+        self.open_stream("/api/chat", &to_body(req)).await
+    }
+
+    pub async fn generate(&self, req: &OllamaGenerateRequest) -> Result<OllamaGenerateResponse, BackendError> {
+        let json = self.post("/api/generate", &to_body(req)).await?;
+        self.parse("/api/generate", &json)
+    }
+
+    pub async fn generate_stream(&self, req: &OllamaGenerateRequest) -> Result<reqwest::Response, BackendError> {
+        self.open_stream("/api/generate", &to_body(req)).await
+    }
+
+    pub async fn show(&self, model: &str) -> Result<OllamaShowResponse, BackendError> {
+        let json = self.post("/api/show", &serde_json::json!({ "model": model })).await?;
+        self.parse("/api/show", &json)
+    }
+
+    // POST a non-streaming body to an Ollama endpoint (e.g. "/api/chat") and return the parsed
+    // JSON reply.
+    pub async fn post(&self, path: &str, body: &Value) -> Result<Value, BackendError> {
+        let state = self.state;
+        let timeout = state.config.request_timeout;
+        let max_attempts = state.config.retry_attempts.max(1);
+        let mut attempt = 1;
+        let mut upstream = state.upstreams.pick();
+        // Retry only what is likely transient while Ollama restarts or loads a model: refused
+        // connections and 503s. Anything else is returned straight away.
+        let resp = loop {
+            let result = state.client.post(upstream.url(path)).timeout(timeout).json(body).send().await;
+            match result {
+                Ok(r) if r.status() == StatusCode::SERVICE_UNAVAILABLE && attempt < max_attempts => {
+                    tracing::warn!(attempt, "ollama returned 503, retrying");
+                }
+                Ok(r) => break r,
+                Err(e) if e.is_connect() => {
+                    upstream.mark_down();
+                    // Another instance that is up takes the request straight away, without
+                    // using up an attempt.
+                    if let Some(next) = state.upstreams.next_up() {
+                        tracing::warn!(ollama = upstream.base(), error = %e, "failed to connect to ollama, trying another instance");
+                        upstream = next;
+                        continue;
+                    }
+                    if attempt >= max_attempts {
+                        tracing::error!(error = %e, "failed to send request to ollama");
+                        return Err(upstream_failure("Error contacting Ollama API", e, timeout));
+                    }
+                    tracing::warn!(attempt, error = %e, "failed to connect to ollama, retrying");
+                }
+                Err(e) => {
+                    tracing::error!(error = %e, "failed to send request to ollama");
+                    return Err(upstream_failure("Error contacting Ollama API", e, timeout));
+                }
+            }
+            tokio::time::sleep(retry_delay(state.config.retry_base_delay, attempt)).await;
+            attempt += 1;
+            upstream = state.upstreams.pick();
+        };
+        let status = resp.status();
+
+        // Read the whole response body as text first so we can log/fallback if parsing fails.
+        let body_text = match resp.text().await {
+            Ok(t) => t,
+            Err(e) => {
+                tracing::error!(error = %e, "failed to read ollama response body");
+                return Err(upstream_failure("Failed to read response body", e, timeout));
+            }
+        };
+
+        if !status.is_success() {
+            let shown = bodies::shown(state.config.log_bodies, &body_text);
+            tracing::warn!(%status, body = %shown, "ollama returned an error");
+            let error = error_text(&body_text);
+            if models::is_model_not_found(status, &error) {
+                return Err(models::model_not_found(state, body["model"].as_str().unwrap_or_default()).await);
+            }
+            return Err(bad_gateway(error, Some(status.as_u16())));
+        }
+
+        // Try to parse JSON; if invalid, report it rather than passing garbage along as a reply.
+        match serde_json::from_str::<Value>(&body_text) {
+            Ok(v) => {
+                if let Some(count) = v["eval_count"].as_u64() {
+                    telemetry::record_tokens(count);
+                }
+                Ok(v)
+            }
+            Err(e) => {
+                let body = bodies::shown(state.config.log_bodies, &body_text);
+                tracing::error!(error = %e, %body, "invalid json from ollama");
+                Err(bad_gateway(format!("Invalid JSON from Ollama: {}", e), Some(status.as_u16())))
+            }
+        }
+    }
+
+    // Read a reply as `T`. Anything else is a 502 rather than a guess at which field holds the
+    // text: a reply that parses as JSON but has the wrong shape is usually an error of some
+    // sort, and passing it along would show it to the user as if the model had said it.
+    fn parse<T: DeserializeOwned>(&self, path: &str, json: &Value) -> Result<T, BackendError> {
+        match T::deserialize(json) {
+            Ok(v) => Ok(v),
+            Err(e) => {
+                let body = bodies::shown(self.state.config.log_bodies, &json.to_string());
+                tracing::error!(error = %e, path, %body, "unexpected reply shape from ollama");
+                Err(bad_gateway(format!("Unexpected reply from Ollama {}: {}", path, e), None))
+            }
+        }
+    }
+
+    // Start a streaming call to an Ollama endpoint. Client errors such as a missing model are
+    // worth a real HTTP status; callers send anything else as an SSE error event.
+    pub async fn open_stream(&self, path: &str, body: &Value) -> Result<reqwest::Response, BackendError> {
+        let state = self.state;
+        // No total timeout here: long generations are expected to stream for a while. The
+        // whole stream comes from the instance that accepted it.
+        let mut upstream = state.upstreams.pick();
+        let resp = loop {
+            match state.client.post(upstream.url(path)).json(body).send().await {
+                Ok(r) => break r,
+                Err(e) => {
+                    if e.is_connect() {
+                        upstream.mark_down();
+                        if let Some(next) = state.upstreams.next_up() {
+                            tracing::warn!(ollama = upstream.base(), error = %e, "failed to connect to ollama, trying another instance");
+                            upstream = next;
+                            continue;
+                        }
+                    }
+                    tracing::error!(error = %e, "failed to send stream request to ollama");
+                    return Err(BackendError::OllamaUnreachable(format!("Error contacting Ollama API: {}", e)));
+                }
+            }
+        };
+
+        if !resp.status().is_success() {
+            let status = resp.status();
+            let txt = match resp.text().await {
+                Ok(t) => t,
+                Err(_) => String::from("unknown error from ollama"),
+            };
+            let shown = bodies::shown(state.config.log_bodies, &txt);
+            tracing::warn!(%status, body = %shown, "ollama returned an error (stream)");
+            let error = error_text(&txt);
+            if models::is_model_not_found(status, &error) {
+                return Err(models::model_not_found(state, body["model"].as_str().unwrap_or_default()).await);
+            }
+            return Err(bad_gateway(error, Some(status.as_u16())));
+        }
+        Ok(resp)
+    }
+}
+
+// Ollama wraps failures as `{"error": "..."}`; surface just the message when it does.
+pub fn error_text(body: &str) -> String {
+    serde_json::from_str::<Value>(body)
+        .ok()
+        .and_then(|v| v["error"].as_str().map(str::to_string))
+        .unwrap_or_else(|| body.to_string())
+}
+
+fn to_body(req: &impl Serialize) -> Value {
+    serde_json::to_value(req).expect("ollama requests are plain data")
+}
+
+// Exponential backoff with up to 50% jitter, so clients that failed together don't all retry
+// in lockstep. The jitter only needs to be spread out, not random, so clock nanos will do.
+fn retry_delay(base: Duration, attempt: u32) -> Duration {
+    let delay = base.saturating_mul(1 << (attempt - 1).min(10));
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos()).unwrap_or(0);
+    delay + delay.mul_f64(f64::from(nanos % 1000) / 2000.0)
+}
@@ -0,0 +1,205 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{
+        sse::{Event, Sse},
+        IntoResponse, Response,
+    },
+    Json,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::{mpsc, OwnedSemaphorePermit};
+
+use crate::stream::{self, SseItem, StreamItem};
+use crate::ollama::OllamaChatRequest;
+use crate::{bodies, model, AppState, BackendError, ChatReq, JsonBody, Msg};
+
+// The subset of OpenAI's chat completion request we translate to Ollama.
+#[derive(Deserialize)]
+pub struct CompletionReq {
+    model: Option<String>,
+    messages: Vec<Msg>,
+    #[serde(default)]
+    stream: bool,
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    seed: Option<i64>,
+    max_tokens: Option<i32>,
+}
+
+impl CompletionReq {
+    fn into_chat_req(self) -> ChatReq {
+        ChatReq {
+            messages: self.messages,
+            model: self.model,
+            stream: Some(self.stream),
+            temperature: self.temperature,
+            top_p: self.top_p,
+            seed: self.seed,
+            num_predict: self.max_tokens,
+            ..Default::default()
+        }
+    }
+}
+
+// POST /v1/chat/completions, so OpenAI SDKs can talk to Ollama through this backend.
+pub async fn chat_completions(
+    State(state): State<AppState>,
+    req: Result<JsonBody<CompletionReq>, BackendError>,
+) -> Response {
+    let req = match req {
+        Ok(JsonBody(req)) => req,
+        Err(e) => return openai_error(e),
+    };
+    let stream = req.stream;
+    let mut req = req.into_chat_req();
+    if let Err(e) = req.validate(&state.config) {
+        return openai_error(e);
+    }
+    let model = model::normalize_model(req.model.as_deref(), &state.config.aliases, &state.config.default_model);
+    if let Err(e) = model::check_allowed(&model, state.config.allowed_models.as_deref()) {
+        return openai_error(e);
+    }
+    tracing::info!(%model, stream, "openai chat completion request");
+    bodies::log_messages(state.config.log_bodies, &req.messages);
+
+    let id = completion_id();
+    let created = unix_now();
+    let body = req.ollama_request(&model, stream, &state.config);
+
+    let permit = match state.generations.acquire().await {
+        Ok(p) => p,
+        Err(e) => return openai_error(e),
+    };
+
+    if stream {
+        return match completion_stream(&state, &body, permit, id, created, model).await {
+            Ok(sse) => sse.into_response(),
+            Err(e) => openai_error(e),
+        };
+    }
+
+    let resp = match state.ollama().chat(&body).await {
+        Ok(r) => r,
+        Err(e) => return openai_error(e),
+    };
+    let content = resp.content();
+    bodies::log_reply(state.config.log_bodies, content);
+    let prompt_tokens = resp.prompt_eval_count.unwrap_or(0);
+    let completion_tokens = resp.eval_count.unwrap_or(0);
+
+    Json(json!({
+        "id": id,
+        "object": "chat.completion",
+        "created": created,
+        "model": model,
+        "choices": [{
+            "index": 0,
+            "message": { "role": "assistant", "content": content },
+            "finish_reason": finish_reason(resp.done_reason.as_deref()),
+        }],
+        "usage": {
+            "prompt_tokens": prompt_tokens,
+            "completion_tokens": completion_tokens,
+            "total_tokens": prompt_tokens + completion_tokens,
+        },
+    }))
+    .into_response()
+}
+
+async fn completion_stream(
+    state: &AppState,
+    body: &OllamaChatRequest,
+    permit: OwnedSemaphorePermit,
+    id: String,
+    created: u64,
+    model: String,
+) -> Result<Sse<stream::Events>, BackendError> {
+    let resp = match state.ollama().chat_stream(body).await {
+        Ok(r) => r,
+        Err(e) if e.is_client_error() => return Err(e),
+        Err(e) => {
+            let data = json!({ "error": { "message": e.message(), "type": "upstream_error" } });
+            return Ok(stream::single_event(Event::default().data(data.to_string())));
+        }
+    };
+
+    let chunk = move |delta: Value, finish: Option<&str>| {
+        let data = json!({
+            "id": id,
+            "object": "chat.completion.chunk",
+            "created": created,
+            "model": model,
+            "choices": [{ "index": 0, "delta": delta, "finish_reason": finish }],
+        });
+        Event::default().data(data.to_string())
+    };
+
+    let (tx, rx) = mpsc::channel::<SseItem>(stream::CHANNEL_CAPACITY);
+    let mut first = true;
+    let encode = move |item| match item {
+        StreamItem::Delta(text) => {
+            // OpenAI announces the role once, on the first chunk.
+            let delta = if first {
+                json!({ "role": "assistant", "content": text })
+            } else {
+                json!({ "content": text })
+            };
+            first = false;
+            (vec![chunk(delta, None)], true)
+        }
+        // This route doesn't take `tools` or `think`, so there are none to pass on.
+        StreamItem::Thinking(_) | StreamItem::ToolCalls(_) => (Vec::new(), true),
+        StreamItem::Done(stats) => {
+            let finish = finish_reason(stats.done_reason.as_deref());
+            (vec![chunk(json!({}), Some(finish)), Event::default().data("[DONE]")], false)
+        }
+        StreamItem::Error(e) => {
+            let data = json!({ "error": { "message": e, "type": "upstream_error" } });
+            (vec![Event::default().data(data.to_string())], false)
+        }
+        StreamItem::Shutdown => {
+            let data = json!({ "error": { "message": stream::SHUTDOWN_MESSAGE, "type": "server_shutdown" } });
+            (vec![Event::default().data(data.to_string())], false)
+        }
+    };
+    let encode = bodies::log_stream(state.config.log_bodies, encode);
+    let encode = stream::with_output_cap(state.config.max_output_tokens, encode);
+    // Registered so graceful shutdown can end it with a `server_shutdown` error.
+    let registration = state.cancellations.register();
+    stream::spawn_forwarder(resp, tx, permit, Some(registration), state.config.stream_idle_timeout, encode);
+    Ok(stream::sse(rx, state.config.sse_keep_alive, state.config.sse_retry))
+}
+
+// Re-shape our error body into OpenAI's `{ "error": { "message", "type" } }`.
+fn openai_error(e: BackendError) -> Response {
+    let status = e.status();
+    let body = e.into_body();
+    let kind = match status {
+        StatusCode::BAD_REQUEST | StatusCode::NOT_FOUND => "invalid_request_error",
+        StatusCode::FORBIDDEN => "permission_error",
+        StatusCode::GATEWAY_TIMEOUT => "timeout",
+        _ => "upstream_error",
+    };
+    (status, Json(json!({ "error": { "message": body.error, "type": kind, "code": body.upstream_status } })))
+        .into_response()
+}
+
+fn finish_reason(done_reason: Option<&str>) -> &'static str {
+    match done_reason {
+        Some("length") | Some("server_limit") => "length",
+        _ => "stop",
+    }
+}
+
+fn completion_id() -> String {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+    format!("chatcmpl-{:x}", nanos)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
@@ -0,0 +1,65 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::ollama::OllamaOptions;
+
+// Default options per model, from MODEL_PRESETS_FILE. A request's own options win over these.
+#[derive(Clone, Debug, Default)]
+pub struct ModelPresets {
+    map: HashMap<String, OllamaOptions>,
+}
+
+impl ModelPresets {
+    // Load a TOML file with one table per model, named as Ollama knows it, e.g.
+    // `["qwen2.5-coder:7b"]` then `temperature = 0.1`. Unknown option names are an error, so a
+    // typo doesn't silently do nothing.
+    pub fn load(path: &Path) -> Result<ModelPresets, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        let map: HashMap<String, OllamaOptions> =
+            toml::from_str(&text).map_err(|e| format!("invalid preset file {}: {}", path.display(), e))?;
+        Ok(ModelPresets { map })
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    // The preset for `model`, or no options at all when it has none.
+    pub fn get(&self, model: &str) -> OllamaOptions {
+        self.map.get(model).cloned().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn presets(text: &str) -> Result<ModelPresets, String> {
+        let map = toml::from_str(text).map_err(|e| e.to_string())?;
+        Ok(ModelPresets { map })
+    }
+
+    #[test]
+    fn reads_options_per_model() {
+        let presets = presets("[\"qwen2.5-coder:7b\"]\ntemperature = 0.1\nnum_ctx = 8192\n").unwrap();
+        let coder = presets.get("qwen2.5-coder:7b");
+        assert_eq!(coder.temperature, Some(0.1));
+        assert_eq!(coder.num_ctx, Some(8192));
+        assert_eq!(coder.top_p, None);
+        assert_eq!(presets.get("llama3:8b"), OllamaOptions::default());
+    }
+
+    #[test]
+    fn leaves_the_server_defaults_what_it_doesnt_set() {
+        let presets = presets("[\"llama3:8b\"]\ntemperature = 0.1\n").unwrap();
+        let defaults = OllamaOptions { temperature: Some(0.7), top_p: Some(0.9), ..Default::default() };
+        let options = presets.get("llama3:8b").or(defaults);
+        assert_eq!(options.temperature, Some(0.1));
+        assert_eq!(options.top_p, Some(0.9));
+    }
+
+    #[test]
+    fn rejects_unknown_options() {
+        assert!(presets("[\"llama3:8b\"]\ntemprature = 0.7\n").is_err());
+    }
+}
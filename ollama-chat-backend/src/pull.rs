@@ -0,0 +1,83 @@
+use axum::{
+    extract::State,
+    response::{sse::Event, IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use crate::stream::{self, SseItem};
+use crate::{bad_request, models, AppState, JsonBody};
+
+#[derive(Deserialize)]
+pub struct PullReq {
+    name: String,
+}
+
+// One line of Ollama's /api/pull progress. Byte counts only appear while a layer downloads.
+#[derive(Deserialize)]
+struct PullLine {
+    #[serde(default)]
+    status: String,
+    total: Option<u64>,
+    completed: Option<u64>,
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Progress {
+    status: String,
+    percent: Option<u64>,
+}
+
+// POST /api/pull: download a model, streaming `{ status, percent }` progress events over SSE.
+// The stream ends after the `success` status, or with an `error` event.
+pub async fn pull(State(state): State<AppState>, JsonBody(req): JsonBody<PullReq>) -> Response {
+    let name = req.name.trim();
+    if name.is_empty() {
+        return bad_request("name must not be empty".to_string()).into_response();
+    }
+    tracing::info!(model = %name, "pull request");
+    if let Err(e) = models::check_single_instance(&state, "pulling") {
+        return e.into_response();
+    }
+
+    let body = serde_json::json!({ "model": name, "stream": true });
+    let resp = match state.ollama().open_stream("/api/pull", &body).await {
+        Ok(r) => r,
+        Err(e) if e.is_client_error() => return e.into_response(),
+        Err(e) => return stream::single_event(stream::error_event(&e.message())).into_response(),
+    };
+
+    let (tx, rx) = mpsc::channel::<SseItem>(stream::CHANNEL_CAPACITY);
+    stream::spawn_ndjson_forwarder(resp, tx, None, None, state.config.stream_idle_timeout, parse_line, |item| {
+        let (events, keep_going) = encode(item);
+        (events.into_iter().map(Ok).collect(), keep_going)
+    });
+    stream::sse(rx, state.config.sse_keep_alive, state.config.sse_retry).into_response()
+}
+
+fn parse_line(line: &str) -> Option<PullLine> {
+    match serde_json::from_str(line) {
+        Ok(l) => Some(l),
+        Err(e) => {
+            tracing::warn!(error = %e, line, "invalid pull progress line from ollama");
+            None
+        }
+    }
+}
+
+fn encode(item: Result<PullLine, String>) -> (Vec<Event>, bool) {
+    let line = match item {
+        Ok(PullLine { error: Some(e), .. }) | Err(e) => return (vec![stream::error_event(&e)], false),
+        Ok(line) => line,
+    };
+    let percent = match (line.completed, line.total) {
+        (Some(completed), Some(total)) if total > 0 => Some(completed.min(total) * 100 / total),
+        _ if line.status == "success" => Some(100),
+        _ => None,
+    };
+    let keep_going = line.status != "success";
+    let progress = Progress { status: line.status, percent };
+    let data = serde_json::to_string(&progress).unwrap_or_default();
+    (vec![Event::default().data(data)], keep_going)
+}
@@ -0,0 +1,113 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+
+use crate::{AppState, ErrorBody};
+
+// Past this many tracked clients, forget the ones whose buckets have refilled.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+// Token bucket per client IP: `per_minute` requests of burst, refilling continuously.
+pub struct RateLimiter {
+    per_minute: u32,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(per_minute: u32) -> RateLimiter {
+        RateLimiter { per_minute, buckets: Mutex::new(HashMap::new()) }
+    }
+
+    // Take `cost` tokens for `ip`, or none and return how many seconds until there are enough.
+    fn check(&self, ip: IpAddr, cost: f64) -> Result<(), u64> {
+        let capacity = self.per_minute as f64;
+        let per_sec = capacity / 60.0;
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() > PRUNE_THRESHOLD {
+            buckets.retain(|_, b| b.tokens + now.duration_since(b.updated).as_secs_f64() * per_sec < capacity);
+        }
+
+        let bucket = buckets.entry(ip).or_insert(Bucket { tokens: capacity, updated: now });
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * per_sec).min(capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= cost {
+            bucket.tokens -= cost;
+            Ok(())
+        } else {
+            Err(((cost - bucket.tokens) / per_sec).ceil() as u64)
+        }
+    }
+
+    pub fn per_minute(&self) -> u32 {
+        self.per_minute
+    }
+}
+
+pub async fn limit(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let Some(limiter) = &state.rate_limiter else {
+        return next.run(req).await;
+    };
+
+    let ip = client_ip(req.headers(), peer, state.config.trust_proxy);
+    if let Err(retry_after) = limiter.check(ip, 1.0) {
+        return too_many_requests(ip, retry_after);
+    }
+    next.run(req).await
+}
+
+// Charge a request that runs several generations for the `extra` ones beyond the first, which
+// `limit` already took. All or nothing: a batch that doesn't fit the client's budget is
+// refused whole rather than run in part. Returns the 429 to send when it doesn't fit.
+pub fn charge_extra(state: &AppState, headers: &HeaderMap, peer: SocketAddr, extra: usize) -> Option<Response> {
+    let limiter = state.rate_limiter.as_ref()?;
+    let ip = client_ip(headers, peer, state.config.trust_proxy);
+    limiter.check(ip, extra as f64).err().map(|retry_after| too_many_requests(ip, retry_after))
+}
+
+fn too_many_requests(ip: IpAddr, retry_after: u64) -> Response {
+    tracing::warn!(%ip, retry_after, "rate limit exceeded");
+    let body = ErrorBody {
+        error: format!("rate limit exceeded, retry in {}s", retry_after),
+        ..Default::default()
+    };
+    let mut resp = (StatusCode::TOO_MANY_REQUESTS, Json(body)).into_response();
+    resp.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+    resp
+}
+
+// X-Forwarded-For is client-controlled, so only honor it when we're known to sit behind a proxy.
+fn client_ip(headers: &HeaderMap, peer: SocketAddr, trust_proxy: bool) -> IpAddr {
+    if trust_proxy {
+        let forwarded = headers
+            .get("x-forwarded-for")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split(',').next())
+            .and_then(|v| v.trim().parse().ok());
+        if let Some(ip) = forwarded {
+            return ip;
+        }
+    }
+    peer.ip()
+}
@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+// Replies to deterministic chat requests (temperature 0 or a pinned seed), so asking the same
+// thing twice doesn't run the model twice. Least recently used entries go first once it is
+// full, and entries older than the TTL are never served.
+pub struct ReplyCache<T> {
+    entries: Mutex<Entries<T>>,
+    max_entries: usize,
+    ttl: Duration,
+}
+
+struct Entries<T> {
+    map: HashMap<Key, Entry<T>>,
+    // Ticks on every lookup and insert; an entry's `used` is the tick it was last touched.
+    clock: u64,
+}
+
+struct Entry<T> {
+    value: T,
+    stored: Instant,
+    used: u64,
+}
+
+// The request serialized whole, so two requests only share a key when they are the same; a
+// hash alone would hand one request's reply to another that happened to collide with it.
+pub type Key = Vec<u8>;
+
+// Cache key for anything that fully describes a request, e.g. (model, messages, options).
+pub fn key(request: &impl Serialize) -> Key {
+    serde_json::to_vec(request).expect("chat requests are plain data")
+}
+
+impl<T: Clone> ReplyCache<T> {
+    pub fn new(max_entries: usize, ttl: Duration) -> ReplyCache<T> {
+        ReplyCache { entries: Mutex::new(Entries { map: HashMap::new(), clock: 0 }), max_entries, ttl }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<T> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.clock += 1;
+        let clock = entries.clock;
+        match entries.map.get_mut(key) {
+            Some(entry) if entry.stored.elapsed() < self.ttl => {
+                entry.used = clock;
+                Some(entry.value.clone())
+            }
+            Some(_) => {
+                entries.map.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn insert(&self, key: Key, value: T) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.clock += 1;
+        let clock = entries.clock;
+        if entries.map.len() >= self.max_entries && !entries.map.contains_key(&key) {
+            let ttl = self.ttl;
+            entries.map.retain(|_, e| e.stored.elapsed() < ttl);
+            // A linear scan; the cache is meant to hold hundreds of replies, not millions.
+            if entries.map.len() >= self.max_entries {
+                if let Some(oldest) = entries.map.iter().min_by_key(|(_, e)| e.used).map(|(k, _)| k.clone()) {
+                    entries.map.remove(&oldest);
+                }
+            }
+        }
+        entries.map.insert(key, Entry { value, stored: Instant::now(), used: clock });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_the_least_recently_used() {
+        let cache = ReplyCache::new(2, Duration::from_secs(60));
+        cache.insert(key(&1), "one");
+        cache.insert(key(&2), "two");
+        assert_eq!(cache.get(&key(&1)), Some("one"));
+        cache.insert(key(&3), "three");
+        assert_eq!(cache.get(&key(&2)), None);
+        assert_eq!(cache.get(&key(&1)), Some("one"));
+        assert_eq!(cache.get(&key(&3)), Some("three"));
+    }
+
+    #[test]
+    fn expires_entries_after_the_ttl() {
+        let cache = ReplyCache::new(2, Duration::ZERO);
+        cache.insert(key(&1), "one");
+        assert_eq!(cache.get(&key(&1)), None);
+    }
+
+    #[test]
+    fn keys_differ_with_the_request() {
+        assert_eq!(key(&("llama3:8b", "hi")), key(&("llama3:8b", "hi")));
+        assert_ne!(key(&("llama3:8b", "hi")), key(&("llama3:8b", "hello")));
+    }
+}
@@ -0,0 +1,80 @@
+use axum::{
+    body::{to_bytes, Body},
+    extract::Request,
+    http::{header, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use serde_json::Value;
+use tracing::Span;
+
+pub const HEADER: &str = "x-request-id";
+
+// Incoming ids longer than this, or with odd characters, are replaced rather than logged.
+const MAX_LEN: usize = 128;
+// Error bodies are small; this only bounds how much gets buffered to tag one.
+const MAX_ERROR_BODY: usize = 64 * 1024;
+
+// Give every request an id, reusing the client's X-Request-Id when it sends a sane one. The id
+// is put on the request for the trace span and `tag_errors`, and echoed in the response header.
+pub async fn assign(mut req: Request, next: Next) -> Response {
+    let id = match req.headers().get(HEADER).and_then(|v| v.to_str().ok()) {
+        Some(id) if is_valid(id) => id.to_string(),
+        _ => uuid::Uuid::new_v4().to_string(),
+    };
+    let value = HeaderValue::from_str(&id).expect("request ids are visible ascii");
+    req.headers_mut().insert(HEADER, value.clone());
+
+    let mut resp = next.run(req).await;
+    resp.headers_mut().insert(HEADER, value);
+    resp
+}
+
+// Add the request id to JSON error bodies, so a failure seen in the browser can be found in
+// the logs. Has to sit inside the compression layer to see the body as plain JSON.
+pub async fn tag_errors(req: Request, next: Next) -> Response {
+    let id = req.headers().get(HEADER).and_then(|v| v.to_str().ok()).unwrap_or_default().to_string();
+    let resp = next.run(req).await;
+    if id.is_empty() || !(resp.status().is_client_error() || resp.status().is_server_error()) {
+        return resp;
+    }
+    add_to_error_body(resp, &id).await
+}
+
+// The span each request's logs are recorded under.
+pub fn make_span(req: &Request) -> Span {
+    let id = req.headers().get(HEADER).and_then(|v| v.to_str().ok()).unwrap_or_default();
+    tracing::info_span!("request", method = %req.method(), uri = %req.uri(), request_id = %id)
+}
+
+fn is_valid(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_LEN && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+pub async fn add_to_error_body(resp: Response, id: &str) -> Response {
+    let is_json = resp
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|v| v.as_bytes().starts_with(b"application/json"));
+    if !is_json {
+        return resp;
+    }
+
+    let (mut parts, body) = resp.into_parts();
+    let bytes = match to_bytes(body, MAX_ERROR_BODY).await {
+        Ok(b) => b,
+        Err(e) => {
+            tracing::warn!(error = %e, "failed to read error body to tag with request id");
+            return Response::from_parts(parts, Body::empty());
+        }
+    };
+    let body = match serde_json::from_slice::<Value>(&bytes) {
+        Ok(Value::Object(mut map)) => {
+            map.insert("request_id".into(), id.into());
+            parts.headers.remove(header::CONTENT_LENGTH);
+            Body::from(Value::Object(map).to_string())
+        }
+        _ => Body::from(bytes),
+    };
+    Response::from_parts(parts, body)
+}
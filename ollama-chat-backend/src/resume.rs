@@ -0,0 +1,144 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::{http::HeaderMap, response::sse::Event};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tracing::Instrument;
+
+use crate::stream::{self, SseItem};
+use crate::{bad_request, BackendError};
+
+pub const HEADER: &str = "last-event-id";
+// Events kept per generation for a client that reconnects; far more than a normal reply has.
+const MAX_EVENTS: usize = 10_000;
+
+// Chat streams a client can reconnect to with Last-Event-ID (STREAM_RESUME_SECS). Every event
+// gets an id, `<generation>:<n>`. A stream keeps generating for `window` after its client goes
+// away, and is kept for `window` once it has finished, so a dropped connection can pick up
+// where it left off instead of starting over.
+pub struct Resumable {
+    window: Duration,
+    streams: Mutex<HashMap<String, mpsc::Sender<Attach>>>,
+}
+
+// A reconnected client, wanting every event after the `after`th.
+struct Attach {
+    tx: mpsc::Sender<SseItem>,
+    after: u64,
+}
+
+impl Resumable {
+    pub fn new(window: Duration) -> Resumable {
+        Resumable { window, streams: Mutex::default() }
+    }
+
+    // Put a relay between generation `id`'s events and its client, numbering and keeping each
+    // event on the way. Returns the sender the generation should write to.
+    pub fn relay(self: &Arc<Self>, id: String, client: mpsc::Sender<SseItem>) -> mpsc::Sender<SseItem> {
+        let (tx, events) = mpsc::channel(stream::CHANNEL_CAPACITY);
+        let (attach, attaches) = mpsc::channel(1);
+        self.streams.lock().unwrap().insert(id.clone(), attach);
+        tokio::spawn(relay(self.clone(), id, events, client, attaches).in_current_span());
+        tx
+    }
+
+    // The rest of the stream the request's Last-Event-ID names, or `None` without the header.
+    pub async fn resume(&self, headers: &HeaderMap) -> Result<Option<mpsc::Receiver<SseItem>>, BackendError> {
+        let Some(value) = headers.get(HEADER) else {
+            return Ok(None);
+        };
+        let last = value.to_str().unwrap_or_default();
+        let Some((id, after)) = last.rsplit_once(':').and_then(|(id, n)| Some((id, n.parse::<u64>().ok()?))) else {
+            return Err(bad_request(format!("{} {:?} is not an event id from this server", HEADER, last)));
+        };
+        let gone = || BackendError::NotFound(format!("generation {:?} is over or unknown and can't be resumed", id));
+        let attach = self.streams.lock().unwrap().get(id).cloned().ok_or_else(gone)?;
+        let (tx, rx) = mpsc::channel(stream::CHANNEL_CAPACITY);
+        attach.send(Attach { tx, after }).await.map_err(|_| gone())?;
+        Ok(Some(rx))
+    }
+}
+
+async fn relay(
+    registry: Arc<Resumable>,
+    id: String,
+    mut events: mpsc::Receiver<SseItem>,
+    client: mpsc::Sender<SseItem>,
+    mut attaches: mpsc::Receiver<Attach>,
+) {
+    let mut kept: VecDeque<(u64, Event)> = VecDeque::new();
+    let mut sent: u64 = 0;
+    let mut client = Some(client);
+    let mut finished = false;
+    // Runs while there's no client: how long the stream waits for one to come back.
+    let deadline = tokio::time::sleep(registry.window);
+    tokio::pin!(deadline);
+    loop {
+        tokio::select! {
+            event = events.recv(), if !finished => match event {
+                Some(Ok(event)) => {
+                    sent += 1;
+                    let event = event.id(format!("{}:{}", id, sent));
+                    kept.push_back((sent, event.clone()));
+                    if kept.len() > MAX_EVENTS {
+                        kept.pop_front();
+                    }
+                    if let Some(tx) = &client {
+                        if tx.send(Ok(event)).await.is_err() {
+                            tracing::info!(generation = %id, "client disconnected, keeping the stream for a reconnect");
+                            client = None;
+                            deadline.as_mut().reset(Instant::now() + registry.window);
+                        }
+                    }
+                }
+                Some(Err(never)) => match never {},
+                // The generation is over; ending the client's stream. It stays here for a
+                // client that missed the end.
+                None => {
+                    finished = true;
+                    client = None;
+                    deadline.as_mut().reset(Instant::now() + registry.window);
+                }
+            },
+            Some(Attach { tx, after }) = attaches.recv() => {
+                if kept.front().is_some_and(|(first, _)| *first > after + 1) {
+                    let message = format!("events after {} are no longer kept; start the chat again", after);
+                    let _ = tx.send(Ok(stream::error_event(&message))).await;
+                    continue;
+                }
+                tracing::info!(generation = %id, after, "client reconnected, resuming the stream");
+                let mut caught_up = true;
+                for (_, event) in kept.iter().filter(|(n, _)| *n > after) {
+                    if tx.send(Ok(event.clone())).await.is_err() {
+                        caught_up = false;
+                        break;
+                    }
+                }
+                // A finished stream ends here, once the client has what it missed.
+                if caught_up && !finished {
+                    client = Some(tx);
+                }
+            }
+            _ = gone(&client) => {
+                tracing::info!(generation = %id, "client disconnected, keeping the stream for a reconnect");
+                client = None;
+                deadline.as_mut().reset(Instant::now() + registry.window);
+            }
+            _ = &mut deadline, if client.is_none() => break,
+        }
+    }
+    if !finished {
+        tracing::info!(generation = %id, "no reconnect in time, aborting the stream");
+    }
+    registry.streams.lock().unwrap().remove(&id);
+    // Dropping `events` closes the forwarder's channel, which stops an unfinished generation.
+}
+
+async fn gone(client: &Option<mpsc::Sender<SseItem>>) {
+    match client {
+        Some(tx) => tx.closed().await,
+        None => std::future::pending().await,
+    }
+}
@@ -0,0 +1,169 @@
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+
+use crate::BackendError;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS conversations (
+    id TEXT PRIMARY KEY,
+    title TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    updated_at INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS messages (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    conversation_id TEXT NOT NULL REFERENCES conversations(id) ON DELETE CASCADE,
+    role TEXT NOT NULL,
+    content TEXT NOT NULL,
+    created_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS messages_by_conversation ON messages(conversation_id, id);
+";
+
+// Timestamps are unix seconds.
+#[derive(Serialize)]
+pub struct Conversation {
+    pub id: String,
+    pub title: String,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+#[derive(Serialize)]
+pub struct StoredMessage {
+    pub role: String,
+    pub content: String,
+    pub created_at: i64,
+}
+
+// Conversation history in SQLite. rusqlite is blocking, so every call runs on the blocking
+// pool behind a single connection; chat traffic is far too light for that to matter.
+pub struct Store {
+    conn: Mutex<Connection>,
+}
+
+impl Store {
+    // Open (creating if needed) the database named by DATABASE_URL, e.g. `sqlite://chats.db`
+    // or just a path.
+    pub fn open(url: &str) -> Result<Store, String> {
+        let path = url.strip_prefix("sqlite://").or_else(|| url.strip_prefix("sqlite:")).unwrap_or(url);
+        let conn = Connection::open(path).map_err(|e| format!("failed to open database {}: {}", path, e))?;
+        conn.execute_batch("PRAGMA foreign_keys = ON;")
+            .and_then(|_| conn.execute_batch(SCHEMA))
+            .map_err(|e| format!("failed to set up database {}: {}", path, e))?;
+        Ok(Store { conn: Mutex::new(conn) })
+    }
+
+    // Run `f` against the connection off the async runtime.
+    async fn call<T, F>(self: &Arc<Self>, f: F) -> Result<T, BackendError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let store = self.clone();
+        let result = tokio::task::spawn_blocking(move || f(&store.conn.lock().unwrap())).await;
+        match result {
+            Ok(Ok(v)) => Ok(v),
+            Ok(Err(e)) => Err(db_error(e.to_string())),
+            Err(e) => Err(db_error(e.to_string())),
+        }
+    }
+
+    pub async fn create_conversation(self: &Arc<Self>, title: String) -> Result<Conversation, BackendError> {
+        self.call(move |conn| {
+            let now = unix_now();
+            let conversation =
+                Conversation { id: uuid::Uuid::new_v4().to_string(), title, created_at: now, updated_at: now };
+            conn.execute(
+                "INSERT INTO conversations (id, title, created_at, updated_at) VALUES (?1, ?2, ?3, ?4)",
+                params![conversation.id, conversation.title, now, now],
+            )?;
+            Ok(conversation)
+        })
+        .await
+    }
+
+    // Most recently active first.
+    pub async fn list_conversations(self: &Arc<Self>) -> Result<Vec<Conversation>, BackendError> {
+        self.call(|conn| {
+            let mut stmt =
+                conn.prepare("SELECT id, title, created_at, updated_at FROM conversations ORDER BY updated_at DESC")?;
+            let rows = stmt.query_map([], conversation_row)?;
+            rows.collect()
+        })
+        .await
+    }
+
+    pub async fn get_conversation(
+        self: &Arc<Self>,
+        id: String,
+    ) -> Result<Option<(Conversation, Vec<StoredMessage>)>, BackendError> {
+        self.call(move |conn| {
+            let conversation = conn
+                .query_row(
+                    "SELECT id, title, created_at, updated_at FROM conversations WHERE id = ?1",
+                    params![id],
+                    conversation_row,
+                )
+                .optional()?;
+            let Some(conversation) = conversation else {
+                return Ok(None);
+            };
+            let mut stmt = conn.prepare(
+                "SELECT role, content, created_at FROM messages WHERE conversation_id = ?1 ORDER BY id",
+            )?;
+            let messages = stmt
+                .query_map(params![id], |row| {
+                    Ok(StoredMessage { role: row.get(0)?, content: row.get(1)?, created_at: row.get(2)? })
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(Some((conversation, messages)))
+        })
+        .await
+    }
+
+    pub async fn exists(self: &Arc<Self>, id: String) -> Result<bool, BackendError> {
+        self.call(move |conn| {
+            conn.query_row("SELECT 1 FROM conversations WHERE id = ?1", params![id], |_| Ok(()))
+                .optional()
+                .map(|found| found.is_some())
+        })
+        .await
+    }
+
+    // Append messages in order. Returns false if the conversation doesn't exist.
+    pub async fn append(self: &Arc<Self>, id: String, messages: Vec<(String, String)>) -> Result<bool, BackendError> {
+        self.call(move |conn| {
+            let now = unix_now();
+            let updated =
+                conn.execute("UPDATE conversations SET updated_at = ?1 WHERE id = ?2", params![now, id])?;
+            if updated == 0 {
+                return Ok(false);
+            }
+            let mut stmt = conn.prepare(
+                "INSERT INTO messages (conversation_id, role, content, created_at) VALUES (?1, ?2, ?3, ?4)",
+            )?;
+            for (role, content) in messages {
+                stmt.execute(params![id, role, content, now])?;
+            }
+            Ok(true)
+        })
+        .await
+    }
+}
+
+fn conversation_row(row: &rusqlite::Row) -> rusqlite::Result<Conversation> {
+    Ok(Conversation { id: row.get(0)?, title: row.get(1)?, created_at: row.get(2)?, updated_at: row.get(3)? })
+}
+
+fn db_error(error: String) -> BackendError {
+    tracing::error!(%error, "database error");
+    BackendError::Internal(format!("database error: {}", error))
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0)
+}
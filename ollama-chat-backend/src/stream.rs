@@ -0,0 +1,791 @@
+use std::convert::Infallible;
+use std::time::{Duration, Instant};
+
+use axum::response::sse::{Event, KeepAlive, Sse};
+use futures_util::stream::{self, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::{mpsc, OwnedSemaphorePermit};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::Instrument;
+
+use crate::cancel::Registration;
+use crate::telemetry;
+
+pub type SseItem = Result<Event, Infallible>;
+// The body of every SSE response: an optional opening `retry:` event, then the channel.
+pub type Events = stream::Chain<stream::Iter<std::option::IntoIter<SseItem>>, ReceiverStream<SseItem>>;
+
+// Events a stream may buffer for a slow client. The forwarder only reads more from Ollama once
+// there's room, so this (plus one upstream chunk) bounds what a stalled client costs.
+pub const CHANNEL_CAPACITY: usize = 16;
+// Longest NDJSON line accepted from Ollama. Real lines are a token or a stats object; anything
+// near this is a broken upstream, and would otherwise grow the buffer without limit.
+const MAX_LINE_BYTES: usize = 1024 * 1024;
+// Why a `format: "json"` reply was refused, streamed or not.
+pub const INVALID_JSON_MESSAGE: &str = "model returned invalid JSON despite format \"json\"";
+// Sent in the `shutdown` event that ends streams still open when the server shuts down.
+pub const SHUTDOWN_MESSAGE: &str = "the server is shutting down";
+
+// Reassembles Ollama's NDJSON stream: bytes come in arbitrary chunks, lines come out whole.
+// Buffering raw bytes (rather than decoding each chunk) keeps multi-byte UTF-8 characters
+// intact when they straddle a chunk boundary; a newline byte never appears inside one.
+#[derive(Default)]
+pub struct LineBuffer {
+    pending: Vec<u8>,
+}
+
+impl LineBuffer {
+    // Append a chunk and return every line it completed. A trailing partial line is kept for
+    // the next call.
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<String> {
+        self.pending.extend_from_slice(bytes);
+        let mut lines = Vec::new();
+        while let Some(idx) = self.pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=idx).collect();
+            if let Some(line) = decode_line(&line) {
+                lines.push(line);
+            }
+        }
+        lines
+    }
+
+    // Bytes held for a line that hasn't ended yet.
+    pub fn pending_len(&self) -> usize {
+        self.pending.len()
+    }
+
+    // Whatever is left once the upstream closes (a final line without a newline).
+    pub fn finish(self) -> Option<String> {
+        decode_line(&self.pending)
+    }
+}
+
+fn decode_line(bytes: &[u8]) -> Option<String> {
+    let line = String::from_utf8_lossy(bytes);
+    let line = line.trim();
+    if line.is_empty() {
+        None
+    } else {
+        Some(line.to_string())
+    }
+}
+
+#[derive(Deserialize)]
+struct ChunkMessage {
+    #[serde(default)]
+    content: String,
+    // Reasoning, from thinking models asked to `think`.
+    #[serde(default)]
+    thinking: String,
+    tool_calls: Option<Value>,
+}
+
+// Timing, token counts and stop reason Ollama attaches to its final `"done": true` object.
+// Durations are in nanoseconds, as Ollama reports them.
+#[derive(Deserialize, Serialize, Default)]
+pub struct DoneStats {
+    // "stop" for a natural end, "length" when num_predict or the context ran out.
+    pub done_reason: Option<String>,
+    pub total_duration: Option<u64>,
+    pub load_duration: Option<u64>,
+    pub prompt_eval_count: Option<u64>,
+    pub prompt_eval_duration: Option<u64>,
+    pub eval_count: Option<u64>,
+    pub eval_duration: Option<u64>,
+}
+
+// One line of Ollama's streaming /api/chat or /api/generate output. Chat puts the delta in
+// `message.content`, generate in `response`.
+#[derive(Deserialize)]
+struct Chunk {
+    message: Option<ChunkMessage>,
+    response: Option<String>,
+    #[serde(default)]
+    done: bool,
+    error: Option<String>,
+    #[serde(flatten)]
+    stats: DoneStats,
+}
+
+pub enum StreamItem {
+    Delta(String),
+    // Reasoning ahead of the answer, kept apart from it.
+    Thinking(String),
+    // Tools the model wants called, in Ollama's shape. Ollama sends these whole, not in pieces.
+    ToolCalls(Value),
+    Done(DoneStats),
+    Error(String),
+    // The server is shutting down and has ended the stream early.
+    Shutdown,
+}
+
+// Turn one NDJSON line into what the client should see. Empty deltas produce nothing.
+pub fn parse_line(line: &str) -> Option<StreamItem> {
+    let chunk: Chunk = match serde_json::from_str(line) {
+        Ok(c) => c,
+        Err(e) => {
+            tracing::warn!(error = %e, line, "invalid stream line from ollama");
+            return None;
+        }
+    };
+
+    if let Some(err) = chunk.error {
+        return Some(StreamItem::Error(err));
+    }
+    if chunk.done {
+        return Some(StreamItem::Done(chunk.stats));
+    }
+    if let Some(calls) = chunk.message.as_ref().and_then(|m| m.tool_calls.clone()) {
+        return Some(StreamItem::ToolCalls(calls));
+    }
+    // Ollama sends reasoning and answer in separate chunks, never both in one.
+    if let Some(thinking) = chunk.message.as_ref().map(|m| &m.thinking).filter(|t| !t.is_empty()) {
+        return Some(StreamItem::Thinking(thinking.clone()));
+    }
+    let delta = chunk.message.map(|m| m.content).or(chunk.response).unwrap_or_default();
+    if delta.is_empty() {
+        None
+    } else {
+        Some(StreamItem::Delta(delta))
+    }
+}
+
+// A named `error` event carrying `{ "message": ... }`, so clients never have to sniff content.
+pub fn error_event(message: &str) -> Event {
+    Event::default().event("error").data(serde_json::json!({ "message": message }).to_string())
+}
+
+// The event encoding used by this backend's own streaming routes: a data event per token
+// delta carrying `{ "content": ... }` (a named `thinking` event for reasoning), a named
+// `tool_calls` event carrying `{ "tool_calls": ... }` when the model calls tools, then a named
+// `done` event carrying Ollama's stats. JSON keeps every payload on one `data:` line, so
+// newlines and carriage returns in the reply arrive exactly as the model wrote them.
+pub fn encode_native(item: StreamItem) -> (Vec<Event>, bool) {
+    match item {
+        StreamItem::Delta(text) => {
+            let data = serde_json::json!({ "content": text }).to_string();
+            (vec![Event::default().data(data)], true)
+        }
+        StreamItem::Thinking(text) => {
+            let data = serde_json::json!({ "content": text }).to_string();
+            (vec![Event::default().event("thinking").data(data)], true)
+        }
+        StreamItem::ToolCalls(calls) => {
+            let data = serde_json::json!({ "tool_calls": calls }).to_string();
+            (vec![Event::default().event("tool_calls").data(data)], true)
+        }
+        StreamItem::Done(stats) => {
+            let data = serde_json::to_string(&stats).unwrap_or_default();
+            (vec![Event::default().event("done").data(data)], false)
+        }
+        StreamItem::Error(e) => (vec![error_event(&e)], false),
+        StreamItem::Shutdown => {
+            let data = serde_json::json!({ "message": SHUTDOWN_MESSAGE }).to_string();
+            (vec![Event::default().event("shutdown").data(data)], false)
+        }
+    }
+}
+
+// `encode_native` with each delta sent as raw text (SSE_TEXT_DELTAS). A newline splits the
+// text over several `data:` lines, which clients have to join back up; carriage returns can't
+// be sent at all, so they're dropped.
+fn encode_native_text(item: StreamItem) -> (Vec<Event>, bool) {
+    match item {
+        StreamItem::Delta(text) => (vec![Event::default().data(text.replace('\r', ""))], true),
+        item => encode_native(item),
+    }
+}
+
+// `encode_native` without the answer's deltas, for JSON replies, which only make sense whole;
+// `with_complete` still sends the full text.
+fn encode_native_whole(item: StreamItem) -> (Vec<Event>, bool) {
+    match item {
+        StreamItem::Delta(_) => (Vec::new(), true),
+        item => encode_native(item),
+    }
+}
+
+// The native encoder SSE_TEXT_DELTAS asks for, or the one for a JSON reply.
+pub fn native_encoder(text_deltas: bool, json_reply: bool) -> fn(StreamItem) -> (Vec<Event>, bool) {
+    if json_reply {
+        encode_native_whole
+    } else if text_deltas {
+        encode_native_text
+    } else {
+        encode_native
+    }
+}
+
+// Wrap an encoder so the whole reply also goes out as a named `complete` event carrying
+// `{ "content": ... }`, plus `thinking` when there was any, just before the `done` event, for
+// clients that want the final text without stitching deltas together.
+pub fn with_complete<F>(mut encode: F) -> impl FnMut(StreamItem) -> (Vec<Event>, bool) + Send + 'static
+where
+    F: FnMut(StreamItem) -> (Vec<Event>, bool) + Send + 'static,
+{
+    let mut content = String::new();
+    let mut thinking = String::new();
+    move |item| {
+        let complete = match &item {
+            StreamItem::Delta(text) => {
+                content.push_str(text);
+                None
+            }
+            StreamItem::Thinking(text) => {
+                thinking.push_str(text);
+                None
+            }
+            StreamItem::Done(_) => {
+                let mut data = serde_json::json!({ "content": std::mem::take(&mut content) });
+                if !thinking.is_empty() {
+                    data["thinking"] = std::mem::take(&mut thinking).into();
+                }
+                Some(Event::default().event("complete").data(data.to_string()))
+            }
+            StreamItem::ToolCalls(_) | StreamItem::Error(_) | StreamItem::Shutdown => None,
+        };
+        let (mut events, keep_going) = encode(item);
+        if let Some(event) = complete {
+            events.insert(0, event);
+        }
+        (events, keep_going)
+    }
+}
+
+// Wrap an encoder so a stream that completes ends with a named `stats` event, timed by this
+// backend rather than Ollama: `time_to_first_token_ms` to the first content delta (`null` if
+// there were none) and `duration_ms`, both from `started` (when the request came in), and
+// `tokens`, the content and reasoning deltas Ollama sent. It goes outside `with_json_check`,
+// which holds deltas back, so it sees them as they arrive.
+pub fn with_stats<F>(started: Instant, mut encode: F) -> impl FnMut(StreamItem) -> (Vec<Event>, bool) + Send + 'static
+where
+    F: FnMut(StreamItem) -> (Vec<Event>, bool) + Send + 'static,
+{
+    let mut first_token: Option<Duration> = None;
+    let mut tokens: u64 = 0;
+    move |item| {
+        let done = match &item {
+            StreamItem::Delta(_) => {
+                first_token.get_or_insert_with(|| started.elapsed());
+                tokens += 1;
+                false
+            }
+            StreamItem::Thinking(_) => {
+                tokens += 1;
+                false
+            }
+            StreamItem::Done(_) => true,
+            StreamItem::ToolCalls(_) | StreamItem::Error(_) | StreamItem::Shutdown => false,
+        };
+        let (mut events, keep_going) = encode(item);
+        if done {
+            let data = serde_json::json!({
+                "time_to_first_token_ms": first_token.map(|d| d.as_millis() as u64),
+                "duration_ms": started.elapsed().as_millis() as u64,
+                "tokens": tokens,
+            });
+            events.push(Event::default().event("stats").data(data.to_string()));
+        }
+        (events, keep_going)
+    }
+}
+
+// Wrap an encoder to check a JSON reply (`format: "json"`) before any of it goes out: deltas are
+// held back until the end, then passed on as one if the whole parses, and replaced with an
+// error if it doesn't. A no-op when `enabled` is false.
+pub fn with_json_check<M, F>(enabled: bool, mut encode: F) -> impl FnMut(StreamItem) -> (Vec<M>, bool) + Send + 'static
+where
+    F: FnMut(StreamItem) -> (Vec<M>, bool) + Send + 'static,
+{
+    let mut reply = String::new();
+    move |item| match item {
+        StreamItem::Delta(text) if enabled => {
+            reply.push_str(&text);
+            (Vec::new(), true)
+        }
+        StreamItem::Done(stats) if enabled => {
+            if serde_json::from_str::<Value>(&reply).is_err() {
+                tracing::warn!("streamed output is not valid json despite format \"json\"");
+                return encode(StreamItem::Error(INVALID_JSON_MESSAGE.to_string()));
+            }
+            let (mut events, keep_going) = encode(StreamItem::Delta(std::mem::take(&mut reply)));
+            if !keep_going {
+                return (events, false);
+            }
+            let (more, keep_going) = encode(StreamItem::Done(stats));
+            events.extend(more);
+            (events, keep_going)
+        }
+        item => encode(item),
+    }
+}
+
+// Wrap an encoder to enforce MAX_OUTPUT_TOKENS: once `cap` deltas (about a token each, reasoning
+// included) have gone out, the stream is finished with `done_reason: "server_limit"` and the
+// upstream request dropped, whatever the model would have done next.
+pub fn with_output_cap<M, F>(cap: Option<u32>, mut encode: F) -> impl FnMut(StreamItem) -> (Vec<M>, bool) + Send + 'static
+where
+    F: FnMut(StreamItem) -> (Vec<M>, bool) + Send + 'static,
+{
+    let mut sent: u64 = 0;
+    move |item| match (item, cap) {
+        (StreamItem::Delta(_) | StreamItem::Thinking(_), Some(cap)) if sent >= u64::from(cap) => {
+            tracing::info!(cap, "stream reached the server output limit, stopping");
+            let stats = DoneStats {
+                done_reason: Some("server_limit".to_string()),
+                eval_count: Some(sent),
+                ..Default::default()
+            };
+            encode(StreamItem::Done(stats))
+        }
+        (item @ (StreamItem::Delta(_) | StreamItem::Thinking(_)), _) => {
+            sent += 1;
+            encode(item)
+        }
+        (item, _) => encode(item),
+    }
+}
+
+// Splits `<tag>...</tag>` reasoning (THINK_TAG) out of reply text, for models that write it
+// inline rather than in Ollama's `thinking` field. Text comes in as it streams, so a tag may
+// arrive in pieces; a possible start of one is held back until the next delta settles it.
+pub struct ThinkSplitter {
+    open: String,
+    close: String,
+    inside: bool,
+    pending: String,
+}
+
+impl ThinkSplitter {
+    pub fn new(tag: &str) -> ThinkSplitter {
+        ThinkSplitter { open: format!("<{}>", tag), close: format!("</{}>", tag), inside: false, pending: String::new() }
+    }
+
+    pub fn push(&mut self, text: &str) -> Vec<StreamItem> {
+        let mut rest = std::mem::take(&mut self.pending) + text;
+        let mut items = Vec::new();
+        loop {
+            let tag = if self.inside { &self.close } else { &self.open };
+            if let Some(i) = rest.find(tag.as_str()) {
+                let after = rest.split_off(i + tag.len());
+                rest.truncate(i);
+                items.extend(self.item(rest));
+                self.inside = !self.inside;
+                rest = after;
+                continue;
+            }
+            // Hold back the longest tail that could still grow into the tag.
+            let held = (1..tag.len()).rev().find(|&n| rest.ends_with(&tag[..n])).unwrap_or(0);
+            self.pending = rest.split_off(rest.len() - held);
+            items.extend(self.item(rest));
+            return items;
+        }
+    }
+
+    // Whatever was held back, once the reply is over.
+    pub fn finish(&mut self) -> Option<StreamItem> {
+        let rest = std::mem::take(&mut self.pending);
+        self.item(rest)
+    }
+
+    fn item(&self, text: String) -> Option<StreamItem> {
+        match (text.is_empty(), self.inside) {
+            (true, _) => None,
+            (false, true) => Some(StreamItem::Thinking(text)),
+            (false, false) => Some(StreamItem::Delta(text)),
+        }
+    }
+}
+
+// Split a whole reply into its reasoning and its answer.
+pub fn split_thinking(tag: &str, text: &str) -> (String, String) {
+    let mut splitter = ThinkSplitter::new(tag);
+    let (mut thinking, mut answer) = (String::new(), String::new());
+    for item in splitter.push(text).into_iter().chain(splitter.finish()) {
+        match item {
+            StreamItem::Thinking(t) => thinking.push_str(&t),
+            StreamItem::Delta(t) => answer.push_str(&t),
+            _ => {}
+        }
+    }
+    (thinking, answer)
+}
+
+// Wrap an encoder to split reasoning out of the reply's deltas with a `ThinkSplitter`; a no-op
+// without a tag.
+pub fn with_think_tags<M, F>(tag: Option<&str>, mut encode: F) -> impl FnMut(StreamItem) -> (Vec<M>, bool) + Send + 'static
+where
+    F: FnMut(StreamItem) -> (Vec<M>, bool) + Send + 'static,
+{
+    let mut splitter = tag.map(ThinkSplitter::new);
+    move |item| {
+        let Some(splitter) = splitter.as_mut() else {
+            return encode(item);
+        };
+        let items = match item {
+            StreamItem::Delta(text) => splitter.push(&text),
+            StreamItem::Done(stats) => splitter.finish().into_iter().chain([StreamItem::Done(stats)]).collect(),
+            item => vec![item],
+        };
+        let mut events = Vec::new();
+        for item in items {
+            let (more, keep_going) = encode(item);
+            events.extend(more);
+            if !keep_going {
+                return (events, false);
+            }
+        }
+        (events, true)
+    }
+}
+
+// An SSE response that carries a single event and then ends, used for errors before streaming.
+pub fn single_event(event: Event) -> Sse<Events> {
+    let (tx, rx) = mpsc::channel::<SseItem>(1);
+    let _ = tx.try_send(Ok(event));
+    sse(rx, None, None)
+}
+
+// Relay `rx` at most one message per `interval`, for a steady typewriter pace instead of
+// bursts at the model's speed. Backpressure still reaches the forwarder, which reads from
+// Ollama only as fast as the paced messages drain.
+pub fn paced<M: Send + 'static>(mut rx: mpsc::Receiver<M>, interval: Option<Duration>) -> mpsc::Receiver<M> {
+    let Some(interval) = interval else {
+        return rx;
+    };
+    let (tx, paced_rx) = mpsc::channel(CHANNEL_CAPACITY);
+    tokio::spawn(
+        async move {
+            let mut ticks = tokio::time::interval(interval);
+            ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                // Watch for the client going away while waiting, so dropping `rx` still
+                // reaches the forwarder straight away.
+                let message = tokio::select! {
+                    _ = tx.closed() => break,
+                    message = rx.recv() => message,
+                };
+                let Some(message) = message else { break };
+                ticks.tick().await;
+                if tx.send(message).await.is_err() {
+                    break;
+                }
+            }
+        }
+        .in_current_span(),
+    );
+    paced_rx
+}
+
+// The SSE response for a forwarder's channel. While the model is quiet, e.g. still loading
+// before its first token, comment pings keep proxies from closing the idle connection.
+// `retry`, if set, opens the stream as a `retry:` field, telling an EventSource how long to wait
+// before reconnecting. It goes out ahead of the channel, so the resume relay never numbers it.
+pub fn sse(rx: mpsc::Receiver<SseItem>, keep_alive: Option<Duration>, retry: Option<Duration>) -> Sse<Events> {
+    let retry = retry.map(|delay| Ok(Event::default().retry(delay)));
+    let sse = Sse::new(stream::iter(retry).chain(ReceiverStream::new(rx)));
+    match keep_alive {
+        Some(interval) => sse.keep_alive(KeepAlive::new().interval(interval)),
+        None => sse,
+    }
+}
+
+// Spawn the task that reads Ollama's streaming chat or generate body and forwards it to `tx`.
+// `encode` turns each item into the SSE events to send, and says whether to keep reading
+// afterwards. The generation permit is held until the stream ends, and `cancel`, if given,
+// can stop it early; at shutdown, it ends the stream with a `shutdown` item instead.
+pub fn spawn_forwarder<F>(
+    resp: reqwest::Response,
+    tx: mpsc::Sender<SseItem>,
+    permit: OwnedSemaphorePermit,
+    cancel: Option<Registration>,
+    idle_timeout: Duration,
+    mut encode: F,
+) where
+    F: FnMut(StreamItem) -> (Vec<Event>, bool) + Send + 'static,
+{
+    spawn_chat_forwarder(resp, tx, permit, cancel, idle_timeout, move |item| {
+        let (events, keep_going) = encode(item);
+        (events.into_iter().map(Ok).collect(), keep_going)
+    });
+}
+
+// `spawn_forwarder` for any transport: `encode` produces whatever `tx` carries, e.g.
+// WebSocket messages rather than SSE events.
+pub fn spawn_chat_forwarder<M, F>(
+    resp: reqwest::Response,
+    tx: mpsc::Sender<M>,
+    permit: OwnedSemaphorePermit,
+    cancel: Option<Registration>,
+    idle_timeout: Duration,
+    mut encode: F,
+) where
+    M: Send + 'static,
+    F: FnMut(StreamItem) -> (Vec<M>, bool) + Send + 'static,
+{
+    let cancel = cancel.map(|registration| (registration, StreamItem::Shutdown));
+    spawn_ndjson_forwarder(resp, tx, Some(permit), cancel, idle_timeout, parse_line, move |item| {
+        let item = item.unwrap_or_else(StreamItem::Error);
+        match &item {
+            StreamItem::Done(stats) => telemetry::record_tokens(stats.eval_count.unwrap_or(0)),
+            StreamItem::Error(_) => telemetry::record_upstream_error("stream"),
+            StreamItem::Delta(_) | StreamItem::Thinking(_) | StreamItem::ToolCalls(_) | StreamItem::Shutdown => {}
+        }
+        encode(item)
+    });
+}
+
+// The general form of `spawn_forwarder` for any of Ollama's NDJSON streams: `parse` turns a
+// line into an item, and `encode` gets either an item or the message for a failed upstream.
+// `cancel` pairs the stream's registration with the item `encode` gets last when the server
+// shuts down.
+// If Ollama goes `idle_timeout` without sending anything, or closes the stream before `encode`
+// has seen its end, the stream ends with an error, on the assumption that it has crashed or
+// wedged.
+pub fn spawn_ndjson_forwarder<T, M, P, F>(
+    resp: reqwest::Response,
+    tx: mpsc::Sender<M>,
+    permit: Option<OwnedSemaphorePermit>,
+    cancel: Option<(Registration, T)>,
+    idle_timeout: Duration,
+    parse: P,
+    mut encode: F,
+) where
+    T: Send + 'static,
+    M: Send + 'static,
+    P: Fn(&str) -> Option<T> + Send + 'static,
+    F: FnMut(Result<T, String>) -> (Vec<M>, bool) + Send + 'static,
+{
+    let mut remote_stream = resp.bytes_stream();
+    let (cancel, mut shutdown) = cancel.unzip();
+
+    tokio::spawn(
+        async move {
+            let mut lines = LineBuffer::default();
+            loop {
+                // Race the next upstream chunk against the client going away, so a closed
+                // EventSource stops generation even while Ollama is still silent (e.g. loading).
+                let chunk = tokio::select! {
+                    _ = tx.closed() => {
+                        tracing::info!("client disconnected, aborting ollama stream");
+                        break;
+                    }
+                    _ = cancelled(cancel.as_ref()) => {
+                        if cancel.as_ref().is_some_and(Registration::is_shutdown) {
+                            tracing::info!("server shutting down, aborting ollama stream");
+                            send_items(&tx, shutdown.take().map(Ok).into_iter().collect(), &mut encode).await;
+                        } else {
+                            tracing::info!("generation cancelled, aborting ollama stream");
+                        }
+                        break;
+                    }
+                    chunk = tokio::time::timeout(idle_timeout, remote_stream.try_next()) => chunk,
+                };
+                let (items, at_end) = match chunk {
+                    Ok(Ok(Some(bytes))) => {
+                        let complete = lines.push(&bytes);
+                        if lines.pending_len() > MAX_LINE_BYTES {
+                            tracing::warn!(limit = MAX_LINE_BYTES, "ollama sent an overlong stream line, aborting");
+                            (vec![Err("Ollama sent a malformed stream".to_string())], true)
+                        } else {
+                            (parse_lines(complete, &parse), false)
+                        }
+                    }
+                    Ok(Ok(None)) => (parse_lines(std::mem::take(&mut lines).finish(), &parse), true),
+                    Ok(Err(e)) => (vec![Err(e.to_string())], true),
+                    Err(_) => {
+                        tracing::warn!(idle_secs = idle_timeout.as_secs(), "ollama stream went idle, aborting");
+                        let msg = format!("Ollama sent nothing for {}s, giving up", idle_timeout.as_secs());
+                        (vec![Err(msg)], true)
+                    }
+                };
+                let keep_going = send_items(&tx, items, &mut encode).await;
+                if keep_going && at_end {
+                    // Ollama closed the stream without its final line (`done`, or an error),
+                    // e.g. because it was killed mid-reply. Say so, rather than leaving the
+                    // client waiting for an end that never comes.
+                    tracing::warn!("ollama stream ended without a done marker");
+                    let interrupted = vec![Err("Ollama's stream ended before the reply was finished".to_string())];
+                    send_items(&tx, interrupted, &mut encode).await;
+                }
+                if !keep_going || at_end {
+                    break;
+                }
+            }
+            // Dropping the body stream closes the upstream connection, which is how Ollama
+            // learns to stop generating.
+            drop(remote_stream);
+            drop(permit);
+            drop(cancel);
+        }
+        .in_current_span(),
+    );
+}
+
+async fn cancelled(cancel: Option<&Registration>) {
+    match cancel {
+        Some(c) => c.cancelled().await,
+        None => std::future::pending().await,
+    }
+}
+
+fn parse_lines<T>(lines: impl IntoIterator<Item = String>, parse: impl Fn(&str) -> Option<T>) -> Vec<Result<T, String>> {
+    lines.into_iter().filter_map(|l| parse(&l)).map(Ok).collect()
+}
+
+// Returns false once the stream should stop, either because `encode` said so or because the
+// client went away. Each send waits for room in the channel, and the caller reads nothing more
+// from Ollama until this returns, so a stalled client stalls the upstream read too (and TCP
+// flow control pushes back on Ollama) instead of piling events up in memory.
+async fn send_items<T, M, F>(tx: &mpsc::Sender<M>, items: Vec<T>, encode: &mut F) -> bool
+where
+    F: FnMut(T) -> (Vec<M>, bool),
+{
+    for item in items {
+        let (messages, keep_going) = encode(item);
+        for message in messages {
+            if tx.send(message).await.is_err() {
+                return false;
+            }
+        }
+        if !keep_going {
+            return false;
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use axum::response::IntoResponse;
+    use futures_util::StreamExt;
+
+    use super::*;
+
+    // An endless upstream that counts how many chunks have been pulled from it.
+    fn counting_upstream(pulled: Arc<AtomicUsize>) -> reqwest::Response {
+        let chunks = futures_util::stream::iter(0..).map(move |i| {
+            pulled.fetch_add(1, Ordering::SeqCst);
+            Ok::<_, std::io::Error>(format!("{{\"message\":{{\"content\":\"t{}\"}}}}\n", i))
+        });
+        reqwest::Response::from(axum::http::Response::new(reqwest::Body::wrap_stream(chunks)))
+    }
+
+    fn forward(resp: reqwest::Response, tx: mpsc::Sender<SseItem>) {
+        spawn_ndjson_forwarder(resp, tx, None, None, Duration::from_secs(5), parse_line, |item| {
+            let (events, keep_going) = encode_native(item.unwrap_or_else(StreamItem::Error));
+            (events.into_iter().map(Ok).collect(), keep_going)
+        });
+    }
+
+    #[tokio::test]
+    async fn slow_consumer_holds_back_upstream_reads() {
+        let pulled = Arc::new(AtomicUsize::new(0));
+        let (tx, mut rx) = mpsc::channel::<SseItem>(CHANNEL_CAPACITY);
+        forward(counting_upstream(pulled.clone()), tx);
+
+        for consumed in 1..=50 {
+            assert!(rx.recv().await.is_some());
+            // Give the forwarder every chance to run ahead of the consumer.
+            tokio::time::sleep(Duration::from_millis(2)).await;
+            let ahead = pulled.load(Ordering::SeqCst) - consumed;
+            // The channel's worth, plus the chunk whose event is waiting for room.
+            assert!(ahead <= CHANNEL_CAPACITY + 1, "read {} chunks ahead of the consumer", ahead);
+        }
+    }
+
+    #[tokio::test]
+    async fn overlong_line_ends_the_stream() {
+        let chunk = vec![b'x'; MAX_LINE_BYTES + 1];
+        let body = reqwest::Body::wrap_stream(futures_util::stream::iter([Ok::<_, std::io::Error>(chunk)]));
+        let (tx, mut rx) = mpsc::channel::<SseItem>(CHANNEL_CAPACITY);
+        forward(reqwest::Response::from(axum::http::Response::new(body)), tx);
+
+        assert!(rx.recv().await.is_some(), "expected an error event");
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn stream_cut_short_ends_with_an_error() {
+        let upstream = "{\"message\":{\"content\":\"Hel\"}}\n";
+        let (tx, rx) = mpsc::channel::<SseItem>(CHANNEL_CAPACITY);
+        forward(reqwest::Response::from(axum::http::Response::new(reqwest::Body::from(upstream))), tx);
+
+        let body = Sse::new(ReceiverStream::new(rx)).into_response().into_body();
+        let bytes = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+        let body = std::str::from_utf8(&bytes).unwrap();
+        assert!(body.starts_with("data: {\"content\":\"Hel\"}\n\n"), "{}", body);
+        assert!(body.ends_with("event: error\ndata: {\"message\":\"Ollama's stream ended before the reply was finished\"}\n\n"), "{}", body);
+    }
+
+    // Read an SSE body back the way EventSource does: events end at a blank line and their
+    // `data:` lines are joined with newlines. Gives the text of the unnamed (delta) events.
+    fn client_reply(body: &str) -> String {
+        body.split("\n\n")
+            .filter(|event| !event.is_empty() && !event.lines().any(|l| l.starts_with("event:")))
+            .map(|event| {
+                let data: Vec<&str> = event.lines().filter_map(|l| l.strip_prefix("data: ")).collect();
+                let delta: serde_json::Value = serde_json::from_str(&data.join("\n")).unwrap();
+                delta["content"].as_str().unwrap().to_string()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn multiline_reply_survives_sse_framing() {
+        let tokens = ["Here:\n\n```rust\n", "fn main() {\r\n", "    println!(\"hi\");\n}\n", "```\n"];
+        let mut upstream: String =
+            tokens.iter().map(|t| format!("{}\n", serde_json::json!({ "message": { "content": t } }))).collect();
+        upstream.push_str("{\"done\":true}\n");
+        let (tx, rx) = mpsc::channel::<SseItem>(CHANNEL_CAPACITY);
+        forward(reqwest::Response::from(axum::http::Response::new(reqwest::Body::from(upstream))), tx);
+
+        let body = Sse::new(ReceiverStream::new(rx)).into_response().into_body();
+        let bytes = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+        assert_eq!(client_reply(std::str::from_utf8(&bytes).unwrap()), tokens.concat());
+    }
+
+    #[test]
+    fn splits_think_tags_arriving_in_pieces() {
+        let mut splitter = ThinkSplitter::new("think");
+        let (mut thinking, mut answer) = (String::new(), String::new());
+        let deltas = ["<th", "ink>Let me", " see.</thi", "nk>", "It's 4 <", "3"];
+        let items = deltas.iter().flat_map(|d| splitter.push(d)).collect::<Vec<_>>();
+        for item in items.into_iter().chain(splitter.finish()) {
+            match item {
+                StreamItem::Thinking(t) => thinking.push_str(&t),
+                StreamItem::Delta(t) => answer.push_str(&t),
+                _ => unreachable!(),
+            }
+        }
+        assert_eq!(thinking, "Let me see.");
+        assert_eq!(answer, "It's 4 <3");
+    }
+
+    #[test]
+    fn joins_lines_split_across_chunks() {
+        let mut buf = LineBuffer::default();
+        assert!(buf.push(b"{\"a\":").is_empty());
+        assert_eq!(buf.push(b"1}\n{\"b\":2}\n{\"c\""), vec!["{\"a\":1}", "{\"b\":2}"]);
+        assert_eq!(buf.finish().as_deref(), Some("{\"c\""));
+    }
+
+    #[test]
+    fn reassembles_multibyte_char_split_across_chunks() {
+        let line = "{\"message\":{\"content\":\"caf\u{e9} \u{1f600}\"}}\n".as_bytes();
+        // Split inside the four-byte emoji.
+        let cut = line.len() - 5;
+        let mut buf = LineBuffer::default();
+        assert!(buf.push(&line[..cut]).is_empty());
+        let lines = buf.push(&line[cut..]);
+        assert_eq!(lines.len(), 1);
+        match parse_line(&lines[0]) {
+            Some(StreamItem::Delta(text)) => assert_eq!(text, "caf\u{e9} \u{1f600}"),
+            _ => panic!("expected a delta"),
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// SYSTEM_PROMPT_TEMPLATE: a system prompt the server puts in front of every chat, with a few
+// placeholders filled in per request. Kept to what the server actually knows.
+pub const PLACEHOLDERS: &[&str] = &["{{date}}", "{{time}}", "{{model}}"];
+
+// Check a template for `{{...}}` placeholders we don't fill in, so a typo fails at startup
+// rather than showing up verbatim in every prompt.
+pub fn validate(template: &str) -> Result<(), String> {
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(len) = rest[start..].find("}}") else {
+            break;
+        };
+        let placeholder = &rest[start..start + len + 2];
+        if !PLACEHOLDERS.contains(&placeholder) {
+            return Err(format!(
+                "SYSTEM_PROMPT_TEMPLATE has an unknown placeholder {}; known ones are {}",
+                placeholder,
+                PLACEHOLDERS.join(", ")
+            ));
+        }
+        rest = &rest[start + len + 2..];
+    }
+    Ok(())
+}
+
+// Fill in the placeholders: `{{date}}` as YYYY-MM-DD and `{{time}}` as HH:MM, both UTC, and
+// `{{model}}` as the model answering.
+pub fn render(template: &str, model: &str, now: SystemTime) -> String {
+    let secs = now.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let minutes = secs % 86_400 / 60;
+    template
+        .replace("{{date}}", &format!("{:04}-{:02}-{:02}", year, month, day))
+        .replace("{{time}}", &format!("{:02}:{:02}", minutes / 60, minutes % 60))
+        .replace("{{model}}", model)
+}
+
+// Days since 1970-01-01 to a (year, month, day) date, after Howard Hinnant's algorithm.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn fills_in_placeholders() {
+        // 2024-02-29 13:05:09 UTC
+        let now = UNIX_EPOCH + Duration::from_secs(1_709_211_909);
+        let rendered = render("Today is {{date}}, {{time}} UTC. You are {{model}}.", "llama3:8b", now);
+        assert_eq!(rendered, "Today is 2024-02-29, 13:05 UTC. You are llama3:8b.");
+    }
+
+    #[test]
+    fn rejects_unknown_placeholders() {
+        assert!(validate("Hi {{user}}, it is {{date}}").is_err());
+        assert!(validate("It is {{date}} and {{time}}; {{model}} here. {{unclosed").is_ok());
+    }
+}
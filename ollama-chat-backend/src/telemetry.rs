@@ -0,0 +1,97 @@
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+
+use crate::stream::StreamItem;
+use crate::AppState;
+
+// Spans quick metadata calls through to long non-streaming generations.
+const DURATION_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0];
+
+// Install the global Prometheus recorder; the handle renders the scrape output.
+pub fn install() -> Result<PrometheusHandle, String> {
+    PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Full("http_request_duration_seconds".to_string()), DURATION_BUCKETS)
+        .map_err(|e| e.to_string())?
+        .install_recorder()
+        .map_err(|e| e.to_string())
+}
+
+// Count and time every request by its route pattern (not the raw path, which would let
+// `/api/models/{name}` grow a series per model). For streams the duration covers only the
+// time until the response started.
+pub async fn track(req: Request, next: Next) -> Response {
+    let route = match req.extensions().get::<MatchedPath>() {
+        Some(path) => path.as_str().to_string(),
+        None => "unmatched".to_string(),
+    };
+    let method = req.method().to_string();
+    let start = Instant::now();
+
+    let resp = next.run(req).await;
+
+    let status = resp.status();
+    metrics::histogram!("http_request_duration_seconds", "method" => method.clone(), "route" => route.clone())
+        .record(start.elapsed().as_secs_f64());
+    metrics::counter!(
+        "http_requests_total",
+        "method" => method,
+        "route" => route,
+        "status" => status.as_u16().to_string()
+    )
+    .increment(1);
+    if status == StatusCode::BAD_GATEWAY || status == StatusCode::GATEWAY_TIMEOUT {
+        record_upstream_error("request");
+    }
+    resp
+}
+
+// GET /metrics in the Prometheus text format.
+pub async fn render(State(state): State<AppState>) -> impl IntoResponse {
+    metrics::gauge!("generations_in_flight").set(state.generations.in_flight() as f64);
+    metrics::gauge!("generations_queued").set(state.generations.queued() as f64);
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], state.metrics.render())
+}
+
+// `stage` is "request" for failed calls and "stream" for streams that broke partway.
+pub fn record_upstream_error(stage: &'static str) {
+    metrics::counter!("ollama_upstream_errors_total", "stage" => stage).increment(1);
+}
+
+pub fn record_tokens(count: u64) {
+    metrics::counter!("tokens_generated_total").increment(count);
+}
+
+// Warn about a chat that took longer than SLOW_REQUEST_MS, with enough to tell which models and
+// prompts are the slow ones.
+pub fn warn_if_slow(threshold: Option<Duration>, started: Instant, model: &str, messages: usize) {
+    let elapsed = started.elapsed();
+    if threshold.is_some_and(|t| elapsed > t) {
+        tracing::warn!(%model, messages, duration_ms = elapsed.as_millis() as u64, "slow chat request");
+    }
+}
+
+// Wrap a stream encoder to `warn_if_slow` once the stream is done.
+pub fn log_slow_stream<M, F>(
+    threshold: Option<Duration>,
+    started: Instant,
+    model: String,
+    messages: usize,
+    mut encode: F,
+) -> impl FnMut(StreamItem) -> (Vec<M>, bool) + Send + 'static
+where
+    F: FnMut(StreamItem) -> (Vec<M>, bool) + Send + 'static,
+{
+    move |item| {
+        if let StreamItem::Done(_) = item {
+            warn_if_slow(threshold, started, &model, messages);
+        }
+        encode(item)
+    }
+}
@@ -0,0 +1,71 @@
+use axum::{extract::State, Json};
+use serde::{Deserialize, Serialize};
+
+use crate::{bad_gateway, bad_request, model, AppState, BackendError, ChatReq, JsonBody, Msg};
+
+// Only the opening of a conversation is needed to name it.
+const MAX_TITLE_MESSAGES: usize = 4;
+const MAX_MESSAGE_CHARS: usize = 1000;
+const MAX_TITLE_CHARS: usize = 60;
+
+const TITLE_PROMPT: &str = "Generate a 3-6 word title for the conversation below. \
+Reply with the title only: no quotes, no punctuation at the end, no explanation.";
+
+#[derive(Deserialize)]
+pub struct TitleReq {
+    messages: Vec<Msg>,
+    model: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct TitleResp {
+    title: String,
+}
+
+// POST /api/chat/title: a short sidebar title for a conversation, generated by the model.
+pub async fn title(State(state): State<AppState>, JsonBody(req): JsonBody<TitleReq>) -> Result<Json<TitleResp>, BackendError> {
+    if req.messages.is_empty() {
+        return Err(bad_request("messages must not be empty".to_string()));
+    }
+    let model = model::normalize_model(req.model.as_deref(), &state.config.aliases, &state.config.default_model);
+    model::check_allowed(&model, state.config.allowed_models.as_deref())?;
+    tracing::info!(%model, "title request");
+
+    let transcript: String = req
+        .messages
+        .iter()
+        .take(MAX_TITLE_MESSAGES)
+        .map(|m| format!("{}: {}\n", m.role, m.content.chars().take(MAX_MESSAGE_CHARS).collect::<String>()))
+        .collect();
+    let prompt = Msg {
+        role: "user".to_string(),
+        content: format!("Conversation:\n{}", transcript),
+        images: None,
+        tool_calls: None,
+    };
+    let chat = ChatReq {
+        messages: vec![prompt],
+        stream: Some(false),
+        system: Some(TITLE_PROMPT.to_string()),
+        temperature: Some(0.2),
+        num_predict: Some(24),
+        ..Default::default()
+    };
+
+    let body = chat.ollama_request(&model, false, &state.config);
+    let _permit = state.generations.acquire().await?;
+    let resp = state.ollama().chat(&body).await?;
+    let title = clean_title(resp.content());
+    if title.is_empty() {
+        return Err(bad_gateway("model returned an empty title".to_string(), None));
+    }
+    Ok(Json(TitleResp { title }))
+}
+
+// Models like to wrap titles in quotes or add a trailing period; keep just the words.
+fn clean_title(raw: &str) -> String {
+    let line = raw.trim().lines().next().unwrap_or_default();
+    let trimmed = line.trim_matches(|c: char| c.is_whitespace() || "\"'`“”‘’*.".contains(c));
+    let title: String = trimmed.chars().take(MAX_TITLE_CHARS).collect();
+    title.trim_end().to_string()
+}
@@ -0,0 +1,35 @@
+use std::path::PathBuf;
+
+use axum_server::tls_rustls::RustlsConfig;
+
+// Where TLS_CERT and TLS_KEY point: a PEM certificate chain and its private key.
+#[derive(Clone, Debug)]
+pub struct TlsPaths {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+// Both or neither; one without the other is almost certainly a typo in the deployment.
+pub fn paths(cert: Option<String>, key: Option<String>) -> Result<Option<TlsPaths>, String> {
+    match (cert, key) {
+        (Some(cert), Some(key)) => Ok(Some(TlsPaths { cert: PathBuf::from(cert), key: PathBuf::from(key) })),
+        (None, None) => Ok(None),
+        (Some(_), None) => Err("TLS_CERT is set but TLS_KEY is not; set both to serve HTTPS".to_string()),
+        (None, Some(_)) => Err("TLS_KEY is set but TLS_CERT is not; set both to serve HTTPS".to_string()),
+    }
+}
+
+// Read and check the certificate and key, so a bad pair fails at startup rather than on the
+// first handshake.
+pub async fn load(paths: &TlsPaths) -> Result<RustlsConfig, String> {
+    // reqwest picks its own provider; the server side uses the process default.
+    let _ = rustls::crypto::ring::default_provider().install_default();
+    RustlsConfig::from_pem_file(&paths.cert, &paths.key).await.map_err(|e| {
+        format!(
+            "failed to load TLS certificate {} and key {}: {}",
+            paths.cert.display(),
+            paths.key.display(),
+            e
+        )
+    })
+}
@@ -0,0 +1,110 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// How long an instance that refused a connection is passed over.
+const DOWN_FOR: Duration = Duration::from_secs(10);
+
+// The Ollama instances requests are spread over (OLLAMA_HOSTS), taken in turn. One that
+// refuses a connection is skipped for a while, as long as another is up.
+pub struct Upstreams {
+    hosts: Vec<Host>,
+    next: AtomicUsize,
+}
+
+struct Host {
+    // Base URL with the path prefix, e.g. "http://gpu1:11434/ollama".
+    base: String,
+    down_until: Mutex<Option<Instant>>,
+}
+
+impl Host {
+    fn is_up(&self) -> bool {
+        self.down_until.lock().unwrap().is_none_or(|until| Instant::now() >= until)
+    }
+}
+
+// One instance, as picked for a request. A stream stays on the instance that started it.
+#[derive(Clone, Copy)]
+pub struct Upstream<'a> {
+    host: &'a Host,
+}
+
+impl Upstreams {
+    pub fn new(hosts: &[String], path_prefix: &str) -> Upstreams {
+        let hosts = hosts
+            .iter()
+            .map(|h| Host { base: format!("{}{}", h, path_prefix), down_until: Mutex::new(None) })
+            .collect();
+        Upstreams { hosts, next: AtomicUsize::new(0) }
+    }
+
+    // The next instance in turn that is up, or just the next one when none are, so a single
+    // instance is always tried.
+    pub fn pick(&self) -> Upstream<'_> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let host = self.in_turn(start).find(|h| h.is_up()).unwrap_or(&self.hosts[start % self.hosts.len()]);
+        Upstream { host }
+    }
+
+    // The next instance in turn that is up; `None` once every one is marked down.
+    pub fn next_up(&self) -> Option<Upstream<'_>> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        self.in_turn(start).find(|h| h.is_up()).map(|host| Upstream { host })
+    }
+
+    pub fn all(&self) -> impl Iterator<Item = Upstream<'_>> {
+        self.hosts.iter().map(|host| Upstream { host })
+    }
+
+    fn in_turn(&self, start: usize) -> impl Iterator<Item = &Host> {
+        let n = self.hosts.len();
+        (0..n).map(move |i| &self.hosts[(start + i) % n])
+    }
+}
+
+impl Upstream<'_> {
+    // Full URL for an Ollama endpoint such as "/api/chat".
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{}", self.host.base, path)
+    }
+
+    pub fn base(&self) -> &str {
+        &self.host.base
+    }
+
+    pub fn mark_down(&self) {
+        *self.host.down_until.lock().unwrap() = Some(Instant::now() + DOWN_FOR);
+    }
+
+    pub fn mark_up(&self) {
+        *self.host.down_until.lock().unwrap() = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upstreams(hosts: &[&str]) -> Upstreams {
+        Upstreams::new(&hosts.iter().map(|h| h.to_string()).collect::<Vec<_>>(), "")
+    }
+
+    #[test]
+    fn takes_instances_in_turn() {
+        let upstreams = upstreams(&["http://a", "http://b"]);
+        let picked: Vec<String> = (0..4).map(|_| upstreams.pick().url("/api/chat")).collect();
+        assert_eq!(picked, ["http://a/api/chat", "http://b/api/chat", "http://a/api/chat", "http://b/api/chat"]);
+    }
+
+    #[test]
+    fn skips_an_instance_that_is_down() {
+        let upstreams = upstreams(&["http://a", "http://b"]);
+        upstreams.pick().mark_down();
+        assert!((0..3).all(|_| upstreams.pick().base() == "http://b"));
+        upstreams.all().for_each(|u| u.mark_down());
+        assert!(upstreams.next_up().is_none());
+        // With every instance down, one is still tried rather than failing outright.
+        upstreams.pick();
+    }
+}
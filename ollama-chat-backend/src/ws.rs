@@ -0,0 +1,186 @@
+use std::time::Duration;
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        State,
+    },
+    response::Response,
+};
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+use tokio::time::{Instant, Interval};
+use tracing::Instrument;
+
+use crate::concurrency::Admission;
+use crate::stream::{self, StreamItem};
+use crate::{bodies, conversations, mock, open_chat_stream, prepare_chat_stream, telemetry, AppState, BackendError, ChatReq, OpenedStream};
+
+// How long a client gets to send its chat request after connecting.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+// GET /api/chat/ws: the streaming chat over a WebSocket, for clients behind proxies that
+// mangle SSE. The client sends one chat request (the same JSON as /api/chat/stream) as a text
+// frame; every frame back is JSON with a `type`:
+//   {"type":"queued","position":...} while waiting for a generation slot, if it has to,
+//   {"type":"model","model":...,"dropped_messages":...}, then
+//   {"type":"delta","content":...} per token, with {"type":"thinking","content":...} for
+//   reasoning and {"type":"tool_calls","tool_calls":...} if the model calls tools, then
+//   {"type":"done", ...stats}, {"type":"error","message":...} or, when the server is stopping,
+//   {"type":"shutdown","message":...}, after which the server closes.
+// Closing the socket stops the generation.
+pub async fn chat_ws(State(state): State<AppState>, upgrade: WebSocketUpgrade) -> Response {
+    let span = tracing::Span::current();
+    upgrade.on_upgrade(move |socket| run(socket, state).instrument(span))
+}
+
+async fn run(mut socket: WebSocket, state: AppState) {
+    let mut req = match read_request(&mut socket).await {
+        Ok(req) => req,
+        Err(message) => return finish(socket, error_frame(&message)).await,
+    };
+
+    let (tx, rx) = mpsc::channel::<Message>(stream::CHANNEL_CAPACITY);
+    let mut rx = stream::paced(rx, req.token_interval());
+    if state.config.mock_mode {
+        if let Err(e) = mock::check_stream(&state, &mut req).await {
+            return finish(socket, error_frame(&e.message())).await;
+        }
+        let _ = tx.try_send(frame(json!({ "type": "model", "model": mock::MODEL })));
+        mock::spawn_reply(&state, req, tx, encode);
+    } else {
+        tokio::spawn(start(state.clone(), req, tx).instrument(tracing::Span::current()));
+    }
+
+    // Pass frames on until the forwarder is done. Returning early drops `rx`, which the
+    // forwarder sees as the client going away.
+    let mut pings = state.config.sse_keep_alive.map(|period| tokio::time::interval_at(Instant::now() + period, period));
+    loop {
+        tokio::select! {
+            message = rx.recv() => match message {
+                Some(message) => {
+                    if socket.send(message).await.is_err() {
+                        return;
+                    }
+                }
+                None => break,
+            },
+            incoming = socket.recv() => match incoming {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => {
+                    tracing::info!("websocket client went away");
+                    return;
+                }
+                Some(Ok(_)) => {}
+            },
+            _ = tick(pings.as_mut()) => {
+                if socket.send(Message::Ping(Vec::new())).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+    let _ = socket.send(Message::Close(None)).await;
+}
+
+// Take a generation slot, telling the client its place in line if it has to wait, then
+// stream the reply into `tx`. Failures go out as an error frame.
+async fn start(state: AppState, mut req: ChatReq, tx: mpsc::Sender<Message>) {
+    let opened = match open(&state, &mut req, &tx).await {
+        Ok(Some(opened)) => opened,
+        Ok(None) => {
+            tracing::info!("websocket client went away while queued");
+            return;
+        }
+        Err(e) => {
+            let _ = tx.send(error_frame(&e.message())).await;
+            return;
+        }
+    };
+    let announce = json!({ "type": "model", "model": opened.used_model, "dropped_messages": opened.dropped_messages });
+    let _ = tx.try_send(frame(announce));
+    let messages = req.messages.len();
+    let json_reply = req.wants_json();
+    let encode = conversations::record_stream(state.clone(), req.conversation_id, req.messages, encode);
+    let encode = stream::with_json_check(json_reply, encode);
+    let encode = bodies::log_stream(state.config.log_bodies, encode);
+    let encode = telemetry::log_slow_stream(state.config.slow_request, opened.started, opened.used_model, messages, encode);
+    let encode = stream::with_output_cap(state.config.max_output_tokens, encode);
+    let encode = stream::with_think_tags(state.config.think_tag.as_deref(), encode);
+    let idle_timeout = state.config.stream_idle_timeout;
+    // Registered so graceful shutdown can end it with a `shutdown` frame.
+    let registration = state.cancellations.register();
+    stream::spawn_chat_forwarder(opened.resp, tx, opened.permit, Some(registration), idle_timeout, encode);
+}
+
+async fn open(state: &AppState, req: &mut ChatReq, tx: &mpsc::Sender<Message>) -> Result<Option<OpenedStream>, BackendError> {
+    let prepared = prepare_chat_stream(state, req).await?;
+    let permit = match state.generations.admit()? {
+        Admission::Ready(permit) => permit,
+        Admission::Queued(waiter) => {
+            let queued = |position| frame(json!({ "type": "queued", "position": position }));
+            match waiter.wait_reporting(tx, queued).await {
+                Some(permit) => permit?,
+                None => return Ok(None),
+            }
+        }
+    };
+    open_chat_stream(state, req, prepared, permit).await.map(Some)
+}
+
+// The first text frame, parsed as a chat request.
+async fn read_request(socket: &mut WebSocket) -> Result<ChatReq, String> {
+    loop {
+        let message = match tokio::time::timeout(REQUEST_TIMEOUT, socket.recv()).await {
+            Ok(Some(Ok(message))) => message,
+            Ok(_) => return Err("connection closed before a chat request was sent".to_string()),
+            Err(_) => return Err(format!("no chat request within {}s", REQUEST_TIMEOUT.as_secs())),
+        };
+        match message {
+            Message::Text(text) => {
+                return serde_json::from_str(&text).map_err(|e| format!("invalid chat request: {}", e));
+            }
+            Message::Close(_) => return Err("connection closed before a chat request was sent".to_string()),
+            Message::Binary(_) => return Err("the chat request must be sent as a text frame".to_string()),
+            Message::Ping(_) | Message::Pong(_) => {}
+        }
+    }
+}
+
+fn encode(item: StreamItem) -> (Vec<Message>, bool) {
+    match item {
+        StreamItem::Delta(text) => (vec![frame(json!({ "type": "delta", "content": text }))], true),
+        StreamItem::Thinking(text) => (vec![frame(json!({ "type": "thinking", "content": text }))], true),
+        StreamItem::ToolCalls(calls) => (vec![frame(json!({ "type": "tool_calls", "tool_calls": calls }))], true),
+        StreamItem::Done(stats) => {
+            let mut data = serde_json::to_value(&stats).unwrap_or_default();
+            data["type"] = "done".into();
+            (vec![frame(data)], false)
+        }
+        StreamItem::Error(e) => (vec![error_frame(&e)], false),
+        StreamItem::Shutdown => (vec![frame(json!({ "type": "shutdown", "message": stream::SHUTDOWN_MESSAGE }))], false),
+    }
+}
+
+fn frame(data: Value) -> Message {
+    Message::Text(data.to_string())
+}
+
+fn error_frame(message: &str) -> Message {
+    frame(json!({ "type": "error", "message": message }))
+}
+
+// Send a last frame and close, for failures before streaming starts.
+async fn finish(mut socket: WebSocket, message: Message) {
+    if socket.send(message).await.is_ok() {
+        let _ = socket.send(Message::Close(None)).await;
+    }
+}
+
+async fn tick(interval: Option<&mut Interval>) {
+    match interval {
+        Some(i) => {
+            i.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
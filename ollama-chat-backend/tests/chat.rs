@@ -0,0 +1,683 @@
+// /api/chat and /api/chat/stream end to end: the real binary against a stub Ollama.
+
+mod common;
+
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use common::{
+    chat_request, sse_events, stub_ollama, stub_ollama_counting, stub_ollama_under, weather_tool, Backend, INSTALLED,
+    JSON_TOKENS, REPLY_TOKENS, THINKING,
+};
+use serde_json::{json, Value};
+
+#[tokio::test]
+async fn chat_returns_the_reply() {
+    let backend = Backend::start(&stub_ollama().await).await;
+    let resp = backend.post("/api/chat", chat_request(INSTALLED)).await;
+    assert_eq!(resp.status(), 200);
+    let body: Value = resp.json().await.unwrap();
+    assert_eq!(body["content"], REPLY_TOKENS.concat());
+    assert_eq!(body["model"], INSTALLED);
+    assert_eq!(body["completion_tokens"], REPLY_TOKENS.len());
+    assert_eq!(body["done_reason"], "stop");
+}
+
+#[tokio::test]
+async fn chat_sends_ollama_timings_as_headers() {
+    let backend = Backend::start(&stub_ollama().await).await;
+    let resp = backend.post("/api/chat", chat_request(INSTALLED)).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["x-ollama-total-ms"], "2");
+    assert_eq!(resp.headers()["x-ollama-eval-ms"], "1");
+    assert_eq!(resp.headers()["x-ollama-prompt-eval-ms"], "0");
+    // The stub doesn't report a load time, so there's no header for it.
+    assert!(!resp.headers().contains_key("x-ollama-load-ms"));
+}
+
+#[tokio::test]
+async fn chat_includes_the_raw_ollama_reply_when_debugging() {
+    let backend = Backend::start(&stub_ollama().await).await;
+    let body: Value = backend.post("/api/chat", chat_request(INSTALLED)).await.json().await.unwrap();
+    assert!(body.get("raw").is_none());
+
+    let body: Value = backend.post("/api/chat?debug=true", chat_request(INSTALLED)).await.json().await.unwrap();
+    assert_eq!(body["raw"]["total_duration"], 2_000_000);
+    assert_eq!(body["raw"]["message"]["content"], REPLY_TOKENS.concat());
+
+    let resp = reqwest::Client::new()
+        .post(format!("{}/api/chat", backend.url))
+        .header("x-debug", "true")
+        .json(&chat_request(INSTALLED))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.json::<Value>().await.unwrap()["raw"]["done_reason"], "stop");
+}
+
+#[tokio::test]
+async fn chat_spreads_requests_over_ollama_hosts() {
+    let (first, first_calls) = stub_ollama_counting().await;
+    let (second, second_calls) = stub_ollama_counting().await;
+    let hosts = format!("{},{}", first, second);
+    let backend = Backend::start_with(&first, &[("OLLAMA_HOSTS", &hosts)]).await;
+    for _ in 0..4 {
+        assert_eq!(backend.post("/api/chat", chat_request(INSTALLED)).await.status(), 200);
+    }
+    assert_eq!(first_calls.load(Ordering::SeqCst), 2);
+    assert_eq!(second_calls.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn chat_skips_an_unreachable_ollama_host() {
+    let (up, calls) = stub_ollama_counting().await;
+    // Nothing listens on port 1, so connecting fails at once.
+    let hosts = format!("http://127.0.0.1:1,{}", up);
+    let backend = Backend::start_with(&up, &[("OLLAMA_HOSTS", &hosts)]).await;
+    for _ in 0..3 {
+        assert_eq!(backend.post("/api/chat", chat_request(INSTALLED)).await.status(), 200);
+    }
+    let resp = backend.post("/api/chat/stream", chat_request(INSTALLED)).await;
+    assert_eq!(resp.status(), 200);
+    assert!(resp.text().await.unwrap().contains("event: complete"));
+    assert_eq!(calls.load(Ordering::SeqCst), 4);
+}
+
+#[tokio::test]
+async fn pull_and_delete_are_refused_with_several_ollama_hosts() {
+    let (first, second) = (stub_ollama().await, stub_ollama().await);
+    let hosts = format!("{},{}", first, second);
+    let backend = Backend::start_with(&first, &[("OLLAMA_HOSTS", &hosts)]).await;
+    assert_eq!(backend.post("/api/pull", json!({ "name": INSTALLED })).await.status(), 501);
+    let resp = reqwest::Client::new().delete(format!("{}/api/models/{}", backend.url, INSTALLED)).send().await.unwrap();
+    assert_eq!(resp.status(), 501);
+}
+
+#[tokio::test]
+async fn chat_replays_a_repeated_idempotency_key() {
+    let (ollama, calls) = stub_ollama_counting().await;
+    let backend = Backend::start(&ollama).await;
+    let send = |body: Value| {
+        reqwest::Client::new()
+            .post(format!("{}/api/chat", backend.url))
+            .header("idempotency-key", "retry-1")
+            .json(&body)
+            .send()
+    };
+    let first = send(chat_request(INSTALLED)).await.unwrap();
+    assert_eq!(first.status(), 200);
+    assert!(!first.headers().contains_key("idempotent-replayed"));
+    let first: Value = first.json().await.unwrap();
+
+    let retry = send(chat_request(INSTALLED)).await.unwrap();
+    assert_eq!(retry.status(), 200);
+    assert_eq!(retry.headers()["idempotent-replayed"], "true");
+    assert_eq!(retry.json::<Value>().await.unwrap(), first);
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+    let mut other = chat_request(INSTALLED);
+    other["messages"][0]["content"] = "something else".into();
+    let resp = send(other).await.unwrap();
+    assert_eq!(resp.status(), 409);
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn chat_refuses_an_idempotency_key_that_is_still_running() {
+    let (ollama, calls) = stub_ollama_counting().await;
+    let backend = Backend::start(&ollama).await;
+    let send = || {
+        reqwest::Client::new()
+            .post(format!("{}/api/chat", backend.url))
+            .header("idempotency-key", "retry-1")
+            .json(&chat_request("slow"))
+            .send()
+    };
+    let first = tokio::spawn(send());
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(send().await.unwrap().status(), 409);
+    assert_eq!(first.await.unwrap().unwrap().status(), 200);
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    // Once it has finished, the retry gets its reply.
+    assert_eq!(send().await.unwrap().headers()["idempotent-replayed"], "true");
+}
+
+#[tokio::test]
+async fn chat_uses_the_default_model() {
+    let backend = Backend::start(&stub_ollama().await).await;
+    let resp = backend.post("/api/chat", json!({ "messages": [{ "role": "user", "content": "hi" }] })).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.json::<Value>().await.unwrap()["model"], INSTALLED);
+}
+
+#[tokio::test]
+async fn chat_rejects_an_empty_conversation() {
+    let backend = Backend::start(&stub_ollama().await).await;
+    let resp = backend.post("/api/chat", json!({ "messages": [] })).await;
+    assert_eq!(resp.status(), 400);
+    assert_eq!(resp.json::<Value>().await.unwrap()["error"], "messages must not be empty");
+}
+
+#[tokio::test]
+async fn chat_rejects_more_messages_than_allowed() {
+    let backend = Backend::start(&stub_ollama().await).await;
+    // One past the default MAX_MESSAGES.
+    let messages = vec![json!({ "role": "user", "content": "again" }); 1001];
+    let resp = backend.post("/api/chat", json!({ "messages": messages })).await;
+    assert_eq!(resp.status(), 400);
+    let error = resp.json::<Value>().await.unwrap()["error"].as_str().unwrap().to_string();
+    assert!(error.starts_with("messages has 1001 entries"), "{}", error);
+}
+
+#[tokio::test]
+async fn chat_rejects_an_overlong_message() {
+    let backend = Backend::start_with(&stub_ollama().await, &[("MAX_MESSAGE_CHARS", "10")]).await;
+    let messages = json!([{ "role": "user", "content": "short" }, { "role": "user", "content": "far too long" }]);
+    let resp = backend.post("/api/chat", json!({ "messages": messages })).await;
+    assert_eq!(resp.status(), 400);
+    let error = resp.json::<Value>().await.unwrap()["error"].as_str().unwrap().to_string();
+    assert_eq!(error, "messages[1].content is 12 characters, more than the 10 allowed");
+}
+
+#[tokio::test]
+async fn chat_renames_roles_from_the_role_map() {
+    let backend = Backend::start_with(&stub_ollama().await, &[("ROLE_MAP", "human=user, ai=assistant")]).await;
+    let messages = json!([{ "role": "human", "content": "hi" }, { "role": "ai", "content": "hello" }, { "role": "human", "content": "bye" }]);
+    let resp = backend.post("/api/chat", json!({ "messages": messages })).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.json::<Value>().await.unwrap()["content"], REPLY_TOKENS.concat());
+
+    let resp = backend.post("/api/chat", json!({ "messages": [{ "role": "bot", "content": "hi" }] })).await;
+    assert_eq!(resp.status(), 400);
+    let error = resp.json::<Value>().await.unwrap()["error"].as_str().unwrap().to_string();
+    assert_eq!(error, "messages[0].role must be one of system, user, assistant, tool, got \"bot\"");
+}
+
+#[tokio::test]
+async fn chat_refuses_a_model_outside_the_allowlist() {
+    let backend = Backend::start_with(&stub_ollama().await, &[("ALLOWED_MODELS", "llama3:8b,phi3")]).await;
+    assert_eq!(backend.post("/api/chat", chat_request(INSTALLED)).await.status(), 200);
+
+    let resp = backend.post("/api/chat/stream", chat_request("llama3:70b")).await;
+    assert_eq!(resp.status(), 403);
+    let error = resp.json::<Value>().await.unwrap()["error"].as_str().unwrap().to_string();
+    assert_eq!(error, "model \"llama3:70b\" is not allowed on this server; use one of: llama3:8b, phi3");
+}
+
+#[tokio::test]
+async fn chat_lists_installed_models_for_a_missing_one() {
+    let backend = Backend::start(&stub_ollama().await).await;
+    let resp = backend.post("/api/chat", chat_request("nope")).await;
+    assert_eq!(resp.status(), 404);
+    let body: Value = resp.json().await.unwrap();
+    assert_eq!(body["error"], "model \"nope\" is not installed");
+    assert_eq!(body["available_models"], json!([INSTALLED]));
+}
+
+#[tokio::test]
+async fn chat_reports_an_upstream_failure_as_bad_gateway() {
+    let backend = Backend::start(&stub_ollama().await).await;
+    let resp = backend.post("/api/chat", chat_request("broken")).await;
+    assert_eq!(resp.status(), 502);
+    let body: Value = resp.json().await.unwrap();
+    assert_eq!(body["error"], "something broke");
+    assert_eq!(body["upstream_status"], 500);
+}
+
+#[tokio::test]
+async fn chat_reports_ollama_being_down() {
+    // Nothing listens on port 9 (discard) here.
+    let backend = Backend::start("http://127.0.0.1:9").await;
+    let resp = backend.post("/api/chat", chat_request(INSTALLED)).await;
+    assert_eq!(resp.status(), 502);
+    let error = resp.json::<Value>().await.unwrap()["error"].as_str().unwrap().to_string();
+    assert!(error.starts_with("Error contacting Ollama API"), "{}", error);
+}
+
+#[tokio::test]
+async fn chat_reaches_ollama_under_a_path_prefix() {
+    let backend = Backend::start_with(&stub_ollama_under("/ollama").await, &[("OLLAMA_PATH_PREFIX", "/ollama/")]).await;
+    let resp = backend.post("/api/chat", chat_request(INSTALLED)).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.json::<Value>().await.unwrap()["content"], REPLY_TOKENS.concat());
+}
+
+#[tokio::test]
+async fn chat_caches_deterministic_replies() {
+    let (ollama, calls) = stub_ollama_counting().await;
+    let backend = Backend::start_with(&ollama, &[("RESPONSE_CACHE_SIZE", "8")]).await;
+    let mut req = chat_request(INSTALLED);
+    req["temperature"] = 0.into();
+    for _ in 0..2 {
+        let body: Value = backend.post("/api/chat", req.clone()).await.json().await.unwrap();
+        assert_eq!(body["content"], REPLY_TOKENS.concat());
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+    // Sampled replies vary, so those always go to Ollama.
+    for _ in 0..2 {
+        backend.post("/api/chat", chat_request(INSTALLED)).await;
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 3);
+}
+
+#[tokio::test]
+async fn batch_answers_each_request_in_order() {
+    let backend = Backend::start(&stub_ollama().await).await;
+    let batch = json!([chat_request(INSTALLED), chat_request("nope"), chat_request(INSTALLED)]);
+    let resp = backend.post("/api/chat/batch", batch).await;
+    assert_eq!(resp.status(), 200);
+    let items: Vec<Value> = resp.json().await.unwrap();
+    assert_eq!(items.len(), 3);
+    assert_eq!(items[0]["content"], REPLY_TOKENS.concat());
+    assert_eq!(items[1]["status"], 404);
+    assert_eq!(items[1]["error"], "model \"nope\" is not installed");
+    assert_eq!(items[2]["content"], REPLY_TOKENS.concat());
+}
+
+#[tokio::test]
+async fn batch_counts_each_request_against_the_rate_limit() {
+    let backend = Backend::start_with(&stub_ollama().await, &[("RATE_LIMIT_PER_MINUTE", "5")]).await;
+    let batch = |n| json!(vec![chat_request(INSTALLED); n]);
+    // Bigger than a whole minute's budget, so it could never run.
+    assert_eq!(backend.post("/api/chat/batch", batch(6)).await.status(), 400);
+    assert_eq!(backend.post("/api/chat/batch", batch(3)).await.status(), 200);
+    // One request's worth is left, not two.
+    let resp = backend.post("/api/chat/batch", batch(2)).await;
+    assert_eq!(resp.status(), 429);
+    assert!(resp.headers().contains_key("retry-after"));
+}
+
+#[tokio::test]
+async fn stream_sends_deltas_then_done() {
+    let backend = Backend::start(&stub_ollama().await).await;
+    let resp = backend.post("/api/chat/stream", chat_request(INSTALLED)).await;
+    assert_eq!(resp.status(), 200);
+    let events = sse_events(&resp.text().await.unwrap());
+    let names: Vec<&str> = events.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(names, ["model", "generation", "message", "message", "message", "complete", "done", "stats"]);
+
+    let model: Value = serde_json::from_str(&events[0].1).unwrap();
+    assert_eq!(model["model"], INSTALLED);
+    let deltas: String = events
+        .iter()
+        .filter(|(name, _)| name == "message")
+        .map(|(_, data)| serde_json::from_str::<Value>(data).unwrap()["content"].as_str().unwrap().to_string())
+        .collect();
+    assert_eq!(deltas, REPLY_TOKENS.concat());
+    let complete: Value = serde_json::from_str(&events[5].1).unwrap();
+    assert_eq!(complete["content"], REPLY_TOKENS.concat());
+    let done: Value = serde_json::from_str(&events[6].1).unwrap();
+    assert_eq!(done["done_reason"], "stop");
+    let stats: Value = serde_json::from_str(&events[7].1).unwrap();
+    assert_eq!(stats["tokens"], REPLY_TOKENS.len());
+    assert!(stats["time_to_first_token_ms"].as_u64().unwrap() <= stats["duration_ms"].as_u64().unwrap());
+}
+
+#[tokio::test]
+async fn chat_streams_when_asked_to() {
+    let backend = Backend::start(&stub_ollama().await).await;
+    let mut req = chat_request(INSTALLED);
+    req["stream"] = true.into();
+    let resp = backend.post("/api/chat", req).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["content-type"], "text/event-stream");
+    let events = sse_events(&resp.text().await.unwrap());
+    assert_eq!(events.first().unwrap().0, "model");
+    assert_eq!(events.last().unwrap().0, "stats");
+}
+
+#[tokio::test]
+async fn chat_passes_tool_calls_back() {
+    let backend = Backend::start(&stub_ollama().await).await;
+    let mut req = chat_request(INSTALLED);
+    req["tools"] = json!([weather_tool()]);
+    let resp = backend.post("/api/chat", req).await;
+    assert_eq!(resp.status(), 200);
+    let body: Value = resp.json().await.unwrap();
+    assert_eq!(body["tool_calls"][0]["function"]["name"], "get_weather");
+    assert_eq!(body["tool_calls"][0]["function"]["arguments"]["city"], "Paris");
+}
+
+#[tokio::test]
+async fn chat_accepts_tool_results() {
+    let backend = Backend::start(&stub_ollama().await).await;
+    let calls = json!([{ "function": { "name": "get_weather", "arguments": { "city": "Paris" } } }]);
+    let req = json!({ "messages": [
+        { "role": "user", "content": "Weather in Paris?" },
+        { "role": "assistant", "content": "", "tool_calls": calls },
+        { "role": "tool", "content": "18C and sunny" },
+    ] });
+    assert_eq!(backend.post("/api/chat", req).await.status(), 200);
+}
+
+#[tokio::test]
+async fn stream_sends_tool_calls_as_their_own_event() {
+    let backend = Backend::start(&stub_ollama().await).await;
+    let mut req = chat_request(INSTALLED);
+    req["tools"] = json!([weather_tool()]);
+    let resp = backend.post("/api/chat/stream", req).await;
+    let events = sse_events(&resp.text().await.unwrap());
+    let (_, data) = events.iter().find(|(name, _)| name == "tool_calls").expect("no tool_calls event");
+    let data: Value = serde_json::from_str(data).unwrap();
+    assert_eq!(data["tool_calls"][0]["function"]["name"], "get_weather");
+    assert!(!events.iter().any(|(name, _)| name == "message"));
+}
+
+#[tokio::test]
+async fn stream_sends_thinking_apart_from_the_answer() {
+    let backend = Backend::start(&stub_ollama().await).await;
+    let mut req = chat_request(INSTALLED);
+    req["think"] = true.into();
+    let events = sse_events(&backend.post("/api/chat/stream", req.clone()).await.text().await.unwrap());
+    let (_, data) = events.iter().find(|(name, _)| name == "thinking").expect("no thinking event");
+    assert_eq!(serde_json::from_str::<Value>(data).unwrap()["content"], THINKING);
+    let (_, data) = events.iter().find(|(name, _)| name == "complete").unwrap();
+    let complete: Value = serde_json::from_str(data).unwrap();
+    assert_eq!(complete["thinking"], THINKING);
+    assert_eq!(complete["content"], REPLY_TOKENS.concat());
+
+    let body: Value = backend.post("/api/chat", req).await.json().await.unwrap();
+    assert_eq!(body["thinking"], THINKING);
+    assert_eq!(body["content"], REPLY_TOKENS.concat());
+}
+
+#[tokio::test]
+async fn stream_paces_events_when_asked() {
+    let backend = Backend::start(&stub_ollama().await).await;
+    let mut req = chat_request(INSTALLED);
+    req["min_token_interval_ms"] = 100.into();
+    let started = std::time::Instant::now();
+    let events = sse_events(&backend.post("/api/chat/stream", req).await.text().await.unwrap());
+    // The stub answers at once; only the pacing spreads the events out.
+    let gaps = events.len() as u32 - 1;
+    assert!(started.elapsed() >= std::time::Duration::from_millis(100) * gaps, "{:?}", started.elapsed());
+    let deltas: Vec<&str> = events.iter().filter(|(name, _)| name == "message").map(|(_, d)| d.as_str()).collect();
+    assert_eq!(deltas.len(), REPLY_TOKENS.len());
+}
+
+#[tokio::test]
+async fn stream_preflight_allows_credentials_from_an_allowed_origin() {
+    let backend = Backend::start(&stub_ollama().await).await;
+    let origin = "http://localhost:5173";
+    let resp = reqwest::Client::new()
+        .request(reqwest::Method::OPTIONS, format!("{}/api/chat/stream", backend.url))
+        .header("origin", origin)
+        .header("access-control-request-method", "POST")
+        .header("access-control-request-headers", "content-type,authorization")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let headers = resp.headers();
+    assert_eq!(headers["access-control-allow-origin"], origin);
+    assert_eq!(headers["access-control-allow-credentials"], "true");
+    let allowed = headers["access-control-allow-headers"].to_str().unwrap();
+    assert!(allowed.contains("content-type") && allowed.contains("authorization"), "{}", allowed);
+    assert!(headers["access-control-allow-methods"].to_str().unwrap().contains("POST"));
+
+    // The stream itself carries the same grant, or the browser would drop it.
+    let resp = reqwest::Client::new()
+        .post(format!("{}/api/chat/stream", backend.url))
+        .header("origin", origin)
+        .json(&chat_request(INSTALLED))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.headers()["access-control-allow-origin"], origin);
+    assert_eq!(resp.headers()["access-control-allow-credentials"], "true");
+}
+
+#[tokio::test]
+async fn stream_reports_a_missing_model_before_streaming() {
+    let backend = Backend::start(&stub_ollama().await).await;
+    let resp = backend.post("/api/chat/stream", chat_request("nope")).await;
+    assert_eq!(resp.status(), 404);
+    assert_eq!(resp.json::<Value>().await.unwrap()["available_models"], json!([INSTALLED]));
+}
+
+#[tokio::test]
+async fn stream_rejects_an_empty_conversation() {
+    let backend = Backend::start(&stub_ollama().await).await;
+    let resp = backend.post("/api/chat/stream", json!({ "messages": [] })).await;
+    assert_eq!(resp.status(), 400);
+}
+
+#[tokio::test]
+async fn stream_sends_an_upstream_failure_as_an_error_event() {
+    let backend = Backend::start(&stub_ollama().await).await;
+    let resp = backend.post("/api/chat/stream", chat_request("broken")).await;
+    assert_eq!(resp.status(), 200);
+    let events = sse_events(&resp.text().await.unwrap());
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].0, "error");
+    assert_eq!(serde_json::from_str::<Value>(&events[0].1).unwrap()["message"], "something broke");
+}
+
+#[tokio::test]
+async fn stream_ends_with_an_error_event_when_ollama_fails_mid_reply() {
+    let backend = Backend::start(&stub_ollama().await).await;
+    let resp = backend.post("/api/chat/stream", chat_request("crashes-mid-stream")).await;
+    let events = sse_events(&resp.text().await.unwrap());
+    let (name, data) = events.last().unwrap();
+    assert_eq!(name, "error");
+    assert_eq!(serde_json::from_str::<Value>(data).unwrap()["message"], "llama runner crashed");
+    assert!(events.iter().any(|(name, data)| name == "message" && data.contains("Hel")));
+}
+
+#[tokio::test]
+async fn stream_sends_a_json_reply_whole() {
+    let backend = Backend::start(&stub_ollama().await).await;
+    let mut req = chat_request("writes-json");
+    req["format"] = json!("json");
+    let resp = backend.post("/api/chat/stream", req).await;
+    let events = sse_events(&resp.text().await.unwrap());
+    assert!(events.iter().all(|(name, _)| name != "message"), "deltas were sent: {:?}", events);
+    let (_, complete) = events.iter().find(|(name, _)| name == "complete").unwrap();
+    let content = serde_json::from_str::<Value>(complete).unwrap()["content"].clone();
+    assert_eq!(content, JSON_TOKENS.concat());
+    let (name, stats) = events.last().unwrap();
+    assert_eq!(name, "stats");
+    // Counted as Ollama sent them, not as the one delta they went out as.
+    assert_eq!(serde_json::from_str::<Value>(stats).unwrap()["tokens"], JSON_TOKENS.len());
+}
+
+#[tokio::test]
+async fn stream_refuses_a_json_reply_that_does_not_parse() {
+    let backend = Backend::start(&stub_ollama().await).await;
+    let mut req = chat_request(INSTALLED);
+    req["format"] = json!("json");
+    let resp = backend.post("/api/chat/stream", req).await;
+    let events = sse_events(&resp.text().await.unwrap());
+    let (name, data) = events.iter().find(|(name, _)| !["model", "generation"].contains(&name.as_str())).unwrap();
+    assert_eq!(name, "error");
+    assert_eq!(serde_json::from_str::<Value>(data).unwrap()["message"], "model returned invalid JSON despite format \"json\"");
+}
+
+#[tokio::test]
+async fn stream_resumes_after_the_last_event_id() {
+    let backend = Backend::start_with(&stub_ollama().await, &[("STREAM_RESUME_SECS", "30")]).await;
+    let mut resp = backend.post("/api/chat/stream", chat_request("hangs-mid-stream")).await;
+    let mut body = String::new();
+    while !body.contains("Hel") {
+        body.push_str(&String::from_utf8_lossy(&resp.chunk().await.unwrap().unwrap()));
+    }
+    drop(resp);
+    let (_, generation) = sse_events(&body).into_iter().find(|(name, _)| name == "generation").unwrap();
+    let id = serde_json::from_str::<Value>(&generation).unwrap()["id"].as_str().unwrap().to_string();
+
+    // Reconnect after the `generation` event, the second one.
+    let resume = |last: String| {
+        reqwest::Client::new()
+            .post(format!("{}/api/chat/stream", backend.url))
+            .header("last-event-id", last)
+            .json(&chat_request("hangs-mid-stream"))
+            .send()
+    };
+    let mut resp = resume(format!("{}:2", id)).await.unwrap();
+    assert_eq!(resp.status(), 200);
+    let mut resumed = String::new();
+    while !resumed.contains("Hel") {
+        resumed.push_str(&String::from_utf8_lossy(&resp.chunk().await.unwrap().unwrap()));
+    }
+    assert!(resumed.contains(&format!("id: {}:3", id)), "{:?}", resumed);
+    assert!(!resumed.contains("generation"), "{:?}", resumed);
+
+    assert_eq!(resume("nope:1".to_string()).await.unwrap().status(), 404);
+}
+
+#[tokio::test]
+async fn stream_opens_with_the_configured_retry_interval() {
+    let ollama = stub_ollama().await;
+    let backend = Backend::start_with(&ollama, &[("SSE_RETRY_MS", "5000")]).await;
+    let body = backend.post("/api/chat/stream", chat_request(INSTALLED)).await.text().await.unwrap();
+    assert!(body.starts_with("retry:5000\n\n"), "{:?}", body);
+    assert_eq!(body.matches("retry:").count(), 1);
+
+    let backend = Backend::start_with(&ollama, &[("SSE_RETRY_MS", "0")]).await;
+    let body = backend.post("/api/chat/stream", chat_request(INSTALLED)).await.text().await.unwrap();
+    assert!(!body.contains("retry:"), "{:?}", body);
+}
+
+#[tokio::test]
+async fn stream_ends_with_a_shutdown_event_when_the_server_stops() {
+    let backend = Backend::start_with(&stub_ollama().await, &[("SHUTDOWN_GRACE_SECS", "1")]).await;
+    let mut resp = backend.post("/api/chat/stream", chat_request("hangs-mid-stream")).await;
+    // Wait for the first delta, so the stream is under way when the signal arrives.
+    let mut body = String::new();
+    while !body.contains("Hel") {
+        body.push_str(&String::from_utf8_lossy(&resp.chunk().await.unwrap().unwrap()));
+    }
+    backend.terminate();
+    while let Some(chunk) = resp.chunk().await.unwrap() {
+        body.push_str(&String::from_utf8_lossy(&chunk));
+    }
+    let events = sse_events(&body);
+    let (name, data) = events.last().unwrap();
+    assert_eq!(name, "shutdown");
+    assert_eq!(serde_json::from_str::<Value>(data).unwrap()["message"], "the server is shutting down");
+}
+
+#[tokio::test]
+async fn openai_stream_ends_with_a_shutdown_error_when_the_server_stops() {
+    let backend = Backend::start_with(&stub_ollama().await, &[("SHUTDOWN_GRACE_SECS", "1")]).await;
+    let mut req = chat_request("hangs-mid-stream");
+    req["stream"] = json!(true);
+    let mut resp = backend.post("/v1/chat/completions", req).await;
+    let mut body = String::new();
+    while !body.contains("Hel") {
+        body.push_str(&String::from_utf8_lossy(&resp.chunk().await.unwrap().unwrap()));
+    }
+    backend.terminate();
+    while let Some(chunk) = resp.chunk().await.unwrap() {
+        body.push_str(&String::from_utf8_lossy(&chunk));
+    }
+    let (_, data) = sse_events(&body).pop().unwrap();
+    assert_eq!(serde_json::from_str::<Value>(&data).unwrap()["error"]["type"], "server_shutdown");
+}
+
+#[tokio::test]
+async fn similarity_ranks_documents_against_the_query() {
+    let backend = Backend::start(&stub_ollama().await).await;
+    let resp = backend.post("/api/similarity", json!({ "query": "cat", "documents": ["dog", "cat", "car"] })).await;
+    assert_eq!(resp.status(), 200);
+    let body: Value = resp.json().await.unwrap();
+    let ranked: Vec<&str> = body["results"].as_array().unwrap().iter().map(|r| r["document"].as_str().unwrap()).collect();
+    assert_eq!(ranked, ["cat", "car", "dog"]);
+    assert_eq!(body["results"][0]["index"], 1);
+    assert!((body["results"][0]["score"].as_f64().unwrap() - 1.0).abs() < 1e-6);
+
+    let resp = backend.post("/api/similarity", json!({ "query": "cat", "documents": [] })).await;
+    assert_eq!(resp.status(), 400);
+}
+
+#[tokio::test]
+async fn generate_fills_in_the_middle_but_chat_refuses_a_suffix() {
+    let backend = Backend::start(&stub_ollama().await).await;
+    let resp = backend.post("/api/generate", json!({ "prompt": "fn add(", "suffix": "}" })).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.json::<Value>().await.unwrap()["response"], "after \"fn add(\" before \"}\"");
+
+    let mut req = chat_request(INSTALLED);
+    req["suffix"] = json!("}");
+    let resp = backend.post("/api/chat", req).await;
+    assert_eq!(resp.status(), 400);
+    assert_eq!(resp.json::<Value>().await.unwrap()["error"], "suffix is only supported by /api/generate");
+}
+
+#[tokio::test]
+async fn generate_streams_the_chat_stream_events() {
+    let backend = Backend::start(&stub_ollama().await).await;
+    let resp = backend.post("/api/generate", json!({ "prompt": "Say hi", "stream": true })).await;
+    assert_eq!(resp.status(), 200);
+    let events = sse_events(&resp.text().await.unwrap());
+    let names: Vec<&str> = events.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(names, ["model", "generation", "message", "message", "message", "complete", "done", "stats"]);
+
+    let generation: Value = serde_json::from_str(&events[1].1).unwrap();
+    assert!(generation["id"].as_str().is_some_and(|id| !id.is_empty()));
+    let complete: Value = serde_json::from_str(&events[5].1).unwrap();
+    assert_eq!(complete["content"], REPLY_TOKENS.concat());
+    let stats: Value = serde_json::from_str(&events[7].1).unwrap();
+    assert_eq!(stats["tokens"], REPLY_TOKENS.len());
+}
+
+#[tokio::test]
+async fn wrong_method_gets_a_json_405() {
+    let backend = Backend::start(&stub_ollama().await).await;
+    let resp = reqwest::get(format!("{}/api/chat", backend.url)).await.unwrap();
+    assert_eq!(resp.status(), 405);
+    assert_eq!(resp.headers()["allow"], "POST");
+    let body: Value = resp.json().await.unwrap();
+    assert_eq!(body["error"], "GET is not allowed on /api/chat; use POST");
+    assert!(body["request_id"].is_string());
+}
+
+#[tokio::test]
+async fn ps_lists_the_loaded_models() {
+    let backend = Backend::start(&stub_ollama().await).await;
+    let resp = backend.get("/api/ps").await;
+    assert_eq!(resp.status(), 200);
+    let models: Value = resp.json().await.unwrap();
+    assert_eq!(
+        models,
+        json!([{
+            "name": INSTALLED,
+            "size": 6_000_000_000u64,
+            "size_vram": 5_000_000_000u64,
+            "expires_at": "2030-01-01T00:05:00Z",
+        }])
+    );
+}
+
+#[tokio::test]
+async fn v2_wraps_replies_in_an_envelope() {
+    let backend = Backend::start(&stub_ollama().await).await;
+    let resp = backend.post("/api/v2/chat", chat_request(INSTALLED)).await;
+    assert_eq!(resp.status(), 200);
+    let id = resp.headers()["x-request-id"].to_str().unwrap().to_string();
+    let body: Value = resp.json().await.unwrap();
+    assert_eq!(body["data"]["content"], REPLY_TOKENS.concat());
+    assert_eq!(body["meta"], json!({ "model": INSTALLED, "request_id": id }));
+
+    let models: Value = backend.get("/api/v2/models").await.json().await.unwrap();
+    assert_eq!(models["data"][0]["name"], INSTALLED);
+    assert_eq!(models["meta"]["model"], Value::Null);
+
+    // Errors and streams look the same as without the prefix.
+    let resp = backend.post("/api/v2/chat", json!({ "messages": [] })).await;
+    assert_eq!(resp.status(), 400);
+    assert_eq!(resp.json::<Value>().await.unwrap()["error"], "messages must not be empty");
+    let stream = backend.post("/api/v2/chat/stream", chat_request(INSTALLED)).await.text().await.unwrap();
+    assert!(sse_events(&stream).iter().any(|(name, _)| name == "complete"));
+}
+
+#[tokio::test]
+async fn unknown_path_gets_a_json_404() {
+    let backend = Backend::start(&stub_ollama().await).await;
+    let resp = backend.post("/api/nope", json!({})).await;
+    assert_eq!(resp.status(), 404);
+    let body: Value = resp.json().await.unwrap();
+    assert_eq!(body["error"], "no route for POST /api/nope");
+    assert!(body["request_id"].is_string());
+}
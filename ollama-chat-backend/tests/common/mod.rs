@@ -0,0 +1,256 @@
+use std::convert::Infallible;
+use std::net::{SocketAddr, TcpListener};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use axum::{
+    body::Body,
+    extract::State,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use futures_util::stream::{self, StreamExt};
+use serde_json::{json, Value};
+
+// What the stub Ollama does depends on the model asked for:
+//   "llama3:8b" answers (the only installed model, and the backend's default),
+//   "broken" fails with a 500, "crashes-mid-stream" sends a token and then an error line,
+//   "hangs-mid-stream" sends a token and then nothing, "writes-json" streams JSON_TOKENS,
+//   "slow" gives a whole reply after half a second, and anything else is a missing model.
+//   Given `tools`, "llama3:8b" calls the first one, and asked to `think` it reasons first.
+pub const INSTALLED: &str = "llama3:8b";
+pub const REPLY_TOKENS: &[&str] = &["Hello", " there,", "\nfriend"];
+pub const THINKING: &str = "They said hi.";
+pub const JSON_TOKENS: &[&str] = &["{\"answer\":", " 42", "}"];
+
+// Start the stub Ollama on a free port and return its base URL.
+pub async fn stub_ollama() -> String {
+    stub_ollama_counting().await.0
+}
+
+// `stub_ollama`, also counting the /api/chat calls it gets.
+pub async fn stub_ollama_counting() -> (String, Arc<AtomicUsize>) {
+    let calls = Arc::new(AtomicUsize::new(0));
+    (serve(routes(calls.clone())).await, calls)
+}
+
+// `stub_ollama` with its API mounted under `prefix`, as behind a gateway.
+pub async fn stub_ollama_under(prefix: &str) -> String {
+    serve(Router::new().nest(prefix, routes(Arc::default()))).await
+}
+
+fn routes(calls: Arc<AtomicUsize>) -> Router {
+    Router::new()
+        .route("/api/chat", post(chat))
+        .route("/api/tags", get(tags))
+        .route("/api/ps", get(ps))
+        .route("/api/embed", post(embed))
+        .route("/api/generate", post(generate))
+        .with_state(calls)
+}
+
+async fn serve(app: Router) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    format!("http://{}", addr)
+}
+
+async fn chat(State(calls): State<Arc<AtomicUsize>>, Json(body): Json<Value>) -> Response {
+    calls.fetch_add(1, Ordering::SeqCst);
+    let model = body["model"].as_str().unwrap_or_default().to_string();
+    let stream = body["stream"].as_bool().unwrap_or(true);
+    if let (INSTALLED, Some(name)) = (model.as_str(), body["tools"][0]["function"]["name"].as_str()) {
+        let calls = json!([{ "function": { "name": name, "arguments": { "city": "Paris" } } }]);
+        let message = json!({ "role": "assistant", "content": "", "tool_calls": calls });
+        return if stream {
+            ndjson(vec![json!({ "message": message, "done": false }), json!({ "done": true, "done_reason": "stop" })])
+        } else {
+            Json(json!({ "message": message, "done": true, "done_reason": "stop" })).into_response()
+        };
+    }
+    let thinks = body["think"].as_bool() == Some(true);
+    match model.as_str() {
+        INSTALLED if stream => {
+            let thinking = thinks.then(|| json!({ "message": { "content": "", "thinking": THINKING }, "done": false }));
+            let tokens = REPLY_TOKENS.iter().map(|t| json!({ "message": { "content": t }, "done": false }));
+            let mut lines: Vec<Value> = thinking.into_iter().chain(tokens).collect();
+            lines.push(json!({ "done": true, "done_reason": "stop", "eval_count": REPLY_TOKENS.len() }));
+            ndjson(lines)
+        }
+        INSTALLED => Json(json!({
+            "message": { "role": "assistant", "content": REPLY_TOKENS.concat(), "thinking": thinks.then_some(THINKING) },
+            "done": true,
+            "done_reason": "stop",
+            "prompt_eval_count": 4,
+            "eval_count": REPLY_TOKENS.len(),
+            "total_duration": 2_000_000,
+            "prompt_eval_duration": 500_000,
+            "eval_duration": 1_000_000,
+        }))
+        .into_response(),
+        "broken" => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": "something broke" }))).into_response(),
+        "crashes-mid-stream" => ndjson(vec![
+            json!({ "message": { "content": "Hel" }, "done": false }),
+            json!({ "error": "llama runner crashed" }),
+        ]),
+        "slow" => {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            Json(json!({ "message": { "role": "assistant", "content": "Finally." }, "done": true })).into_response()
+        }
+        "writes-json" => {
+            let tokens = JSON_TOKENS.iter().map(|t| json!({ "message": { "content": t }, "done": false }));
+            ndjson(tokens.chain([json!({ "done": true, "done_reason": "stop" })]).collect())
+        }
+        "hangs-mid-stream" => {
+            let line = format!("{}\n", json!({ "message": { "content": "Hel" }, "done": false }));
+            let body = stream::once(async move { Ok::<_, Infallible>(line) }).chain(stream::pending());
+            ([(header::CONTENT_TYPE, "application/x-ndjson")], Body::from_stream(body)).into_response()
+        }
+        _ => {
+            let error = format!("model \"{}\" not found, try pulling it first", model);
+            (StatusCode::NOT_FOUND, Json(json!({ "error": error }))).into_response()
+        }
+    }
+}
+
+async fn tags() -> Json<Value> {
+    Json(json!({ "models": [{ "name": INSTALLED, "size": 1, "modified_at": "" }] }))
+}
+
+async fn ps() -> Json<Value> {
+    let loaded = json!({
+        "name": INSTALLED,
+        "model": INSTALLED,
+        "size": 6_000_000_000u64,
+        "size_vram": 5_000_000_000u64,
+        "expires_at": "2030-01-01T00:05:00Z",
+        "details": { "family": "llama" },
+    });
+    Json(json!({ "models": [loaded] }))
+}
+
+// Says what it was given, so tests can see what the backend forwarded; streamed, it sends
+// REPLY_TOKENS instead.
+async fn generate(Json(body): Json<Value>) -> Response {
+    if body["stream"].as_bool() == Some(true) {
+        let mut lines: Vec<Value> = REPLY_TOKENS.iter().map(|t| json!({ "response": t, "done": false })).collect();
+        lines.push(json!({ "response": "", "done": true, "done_reason": "stop" }));
+        return ndjson(lines);
+    }
+    let response = format!("after {} before {}", body["prompt"], body["suffix"]);
+    Json(json!({ "response": response, "done": true })).into_response()
+}
+
+// Each input's letter counts, so texts that share letters come out similar.
+async fn embed(Json(body): Json<Value>) -> Json<Value> {
+    let inputs: Vec<&str> = match &body["input"] {
+        Value::Array(items) => items.iter().filter_map(Value::as_str).collect(),
+        other => other.as_str().into_iter().collect(),
+    };
+    let embeddings: Vec<Vec<f32>> = inputs
+        .iter()
+        .map(|text| (b'a'..=b'z').map(|c| text.bytes().filter(|&b| b == c).count() as f32).collect())
+        .collect();
+    Json(json!({ "embeddings": embeddings }))
+}
+
+fn ndjson(lines: Vec<Value>) -> Response {
+    let body: String = lines.iter().map(|l| format!("{}\n", l)).collect();
+    ([(header::CONTENT_TYPE, "application/x-ndjson")], Body::from(body)).into_response()
+}
+
+// The backend binary, running against `ollama_host` until dropped.
+pub struct Backend {
+    child: Child,
+    pub url: String,
+}
+
+impl Backend {
+    pub async fn start(ollama_host: &str) -> Backend {
+        Backend::start_with(ollama_host, &[]).await
+    }
+
+    // `start`, with extra environment variables for the backend.
+    pub async fn start_with(ollama_host: &str, env: &[(&str, &str)]) -> Backend {
+        // Take a free port from the OS; the backend binds it right after.
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let child = Command::new(env!("CARGO_BIN_EXE_ollama-chat-backend"))
+            .env_clear()
+            .env("OLLAMA_HOST", ollama_host)
+            .env("PORT", port.to_string())
+            .env("RATE_LIMIT_PER_MINUTE", "0")
+            .env("OLLAMA_RETRY_ATTEMPTS", "1")
+            .env("RUST_LOG", "warn")
+            .envs(env.iter().copied())
+            .stdout(Stdio::null())
+            .spawn()
+            .expect("failed to start the backend");
+        let backend = Backend { child, url: format!("http://{}", SocketAddr::from(([127, 0, 0, 1], port))) };
+        backend.wait_until_up().await;
+        backend
+    }
+
+    async fn wait_until_up(&self) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while reqwest::get(format!("{}/health", self.url)).await.is_err() {
+            assert!(Instant::now() < deadline, "backend did not come up");
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    pub async fn post(&self, path: &str, body: Value) -> reqwest::Response {
+        reqwest::Client::new().post(format!("{}{}", self.url, path)).json(&body).send().await.unwrap()
+    }
+
+    pub async fn get(&self, path: &str) -> reqwest::Response {
+        reqwest::get(format!("{}{}", self.url, path)).await.unwrap()
+    }
+
+    // Ask the backend to shut down gracefully, as a process manager would.
+    pub fn terminate(&self) {
+        let status = Command::new("kill").arg("-TERM").arg(self.child.id().to_string()).status().unwrap();
+        assert!(status.success(), "failed to signal the backend");
+    }
+}
+
+impl Drop for Backend {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// A tool definition in Ollama's (and OpenAI's) format.
+pub fn weather_tool() -> Value {
+    json!({
+        "type": "function",
+        "function": {
+            "name": "get_weather",
+            "description": "Current weather for a city",
+            "parameters": { "type": "object", "properties": { "city": { "type": "string" } }, "required": ["city"] },
+        },
+    })
+}
+
+// A chat request for `model` with a single user message.
+pub fn chat_request(model: &str) -> Value {
+    json!({ "model": model, "messages": [{ "role": "user", "content": "hi" }] })
+}
+
+// Split an SSE body into (event name, data) pairs the way EventSource does: unnamed events are
+// "message", and multiple `data:` lines are joined with newlines.
+pub fn sse_events(body: &str) -> Vec<(String, String)> {
+    body.split("\n\n")
+        .filter(|block| block.lines().any(|l| l.starts_with("data:")))
+        .map(|block| {
+            let event = block.lines().find_map(|l| l.strip_prefix("event: ")).unwrap_or("message");
+            let data: Vec<&str> = block.lines().filter_map(|l| l.strip_prefix("data: ")).collect();
+            (event.to_string(), data.join("\n"))
+        })
+        .collect()
+}
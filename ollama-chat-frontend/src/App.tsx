@@ -120,7 +120,10 @@ export default function App() {
   const [busy, setBusy] = useState(false);
   const [editingIndex, setEditingIndex] = useState<number | null>(null);
   const [editingTitle, setEditingTitle] = useState<string | null>(null);
+  // Shown in place of the thinking indicator's text while a reply waits, e.g. in the queue.
+  const [status, setStatus] = useState<string | null>(null);
   const controllerRef = useRef<AbortController | null>(null);
+  const generationIdRef = useRef<string | null>(null);
   const bufferRef = useRef("");
   const flushTimerRef = useRef<number | null>(null);
   const chatRef = useRef<HTMLDivElement | null>(null);
@@ -273,6 +276,7 @@ export default function App() {
       const decoder = new TextDecoder();
       let buffer = "";
       let done = false;
+      let gotDeltas = false;
 
       const flushBuffer = () => {
         const txt = bufferRef.current;
@@ -315,28 +319,98 @@ export default function App() {
             if (idx === -1) break;
             const rawEvent = buffer.slice(0, idx);
             buffer = buffer.slice(idx + 2);
+            // Each unnamed event carries one token delta as `{ content }`; per the SSE spec strip a
+            // single space after "data:".
             const lines = rawEvent.split(/\r?\n/);
-            const dataLines = lines.filter(l => l.startsWith("data:")).map(l => l.slice(5).trim()).join("\n");
-            let data = dataLines.trim();
-            while (data.startsWith("data:")) data = data.slice(5).trim();
-            if (!data) continue;
-            if (data.startsWith("__ERR__:") || data.toLowerCase().startsWith("error")) {
-              bufferRef.current += `\n[error] ${data}`;
-            } else {
+            const eventType = lines.find(l => l.startsWith("event:"))?.slice(6).trim() ?? "message";
+            const data = lines
+              .filter(l => l.startsWith("data:"))
+              .map(l => l.slice(l.startsWith("data: ") ? 6 : 5))
+              .join("\n");
+            if (eventType === "done") {
               try {
-                const obj = JSON.parse(data);
-                const msg = obj?.message?.content;
-                if (typeof msg === 'string' && msg.length > 0) {
-                  bufferRef.current += msg;
+                if (JSON.parse(data)?.done_reason === "length") {
+                  bufferRef.current += "\n[truncated: the reply hit the token limit]";
                 }
-                if (obj?.done === true) {
-                  flushBuffer();
-                  done = true;
-                  break;
+              } catch {
+                // stats are optional
+              }
+              flushBuffer();
+              done = true;
+              break;
+            }
+            if (eventType === "generation") {
+              try {
+                generationIdRef.current = JSON.parse(data)?.id ?? null;
+              } catch {
+                // cancelling falls back to closing the connection
+              }
+              continue;
+            }
+            if (eventType === "queued") {
+              let position = "";
+              try {
+                position = ` (position ${JSON.parse(data)?.position})`;
+              } catch {
+                // no position to show
+              }
+              setStatus(`Waiting for a free slot${position}…`);
+              continue;
+            }
+            // The model's reasoning (`think: true` or THINK_TAG) is never part of the answer; it
+            // only tells the user the model is working on one.
+            if (eventType === "thinking") {
+              setStatus("Reasoning…");
+              continue;
+            }
+            // Any other event after `queued` means the reply has its slot.
+            setStatus(null);
+            // This chat offers the model no tools, so a tool call has nothing to run or show, and
+            // its JSON array is not reply text.
+            if (eventType === "tool_calls") continue;
+            // The backend is restarting and has ended the stream; the reply so far is all there is.
+            if (eventType === "shutdown") {
+              bufferRef.current += "\n[the server is restarting; send your message again in a moment]";
+              flushBuffer();
+              done = true;
+              break;
+            }
+            // Usually the deltas already add up to the reply, so `complete` has nothing new for
+            // us. A `format: "json"` reply is checked whole before it is sent, and then arrives
+            // only here.
+            if (eventType === "complete") {
+              if (!gotDeltas) {
+                try {
+                  bufferRef.current += JSON.parse(data)?.content ?? "";
+                } catch {
+                  // nothing to show
                 }
-              } catch (e) {
-                bufferRef.current += data;
+                flushBuffer();
               }
+              continue;
+            }
+            // Only unnamed events and errors go into the reply; any other named event is skipped,
+            // so a backend that adds one doesn't leak its payload into the chat.
+            if (!data || (eventType !== "message" && eventType !== "error")) continue;
+            if (eventType === "error") {
+              let message = data;
+              try {
+                message = JSON.parse(data)?.message ?? data;
+              } catch {
+                // keep the raw payload
+              }
+              bufferRef.current += `\n[error] ${message}`;
+            } else {
+              // A backend with SSE_TEXT_DELTAS on sends the raw text instead.
+              let delta = data;
+              try {
+                const parsed = JSON.parse(data);
+                if (typeof parsed?.content === "string") delta = parsed.content;
+              } catch {
+                // raw text
+              }
+              bufferRef.current += delta;
+              gotDeltas = true;
             }
             if (!flushTimerRef.current) {
               flushTimerRef.current = window.setTimeout(() => flushBuffer(), 80);
@@ -345,6 +419,7 @@ export default function App() {
         }
         if (streamDone) done = true;
       }
+      flushBuffer();
 
     } catch (e: any) {
       const isAbort = e?.name === 'AbortError' || e?.message?.toLowerCase()?.includes('aborted');
@@ -356,7 +431,9 @@ export default function App() {
       updateConversationMessages(activeId, [...currentMessages.slice(0, nextMessages.length), assistantMsg]);
     } finally {
       setBusy(false);
+      setStatus(null);
       controllerRef.current = null;
+      generationIdRef.current = null;
       if (flushTimerRef.current) {
         window.clearTimeout(flushTimerRef.current);
         flushTimerRef.current = null;
@@ -365,6 +442,11 @@ export default function App() {
   }
 
   function cancel() {
+    // Ask the backend to stop explicitly; a dropped connection isn't always noticed behind a proxy.
+    if (generationIdRef.current) {
+      fetch(`http://127.0.0.1:8080/api/cancel/${generationIdRef.current}`, { method: "POST" }).catch(() => {});
+      generationIdRef.current = null;
+    }
     if (controllerRef.current) {
       controllerRef.current.abort();
       controllerRef.current = null;
@@ -470,7 +552,7 @@ export default function App() {
           ))}
           {busy && (
             <div className="thinking-indicator">
-              <span>AI is thinking...</span>
+              <span>{status ?? "AI is thinking..."}</span>
             </div>
           )}
         </div>